@@ -1,45 +1,1040 @@
-use anyhow::Result;
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
 use argh::FromArgs;
-use trenza::merge::merge_repositories;
+use trenza::git::{GitRunner, Identity, Signing};
+use trenza::merge::{
+    check_git_installed, check_repositories, discover_repositories, load_branch_map, load_path_map,
+    merge_repositories, split_subdir, undo_merge, Config, DeadlineExceeded, DiscoveryOptions,
+    EolMode, GitNotFound, LfsMode, MergeOptions, MergeStrategy, OnCollision, OnConflict, RepoOrder,
+    SubmoduleMode, DEFAULT_DISCOVERY_PATTERN, DEFAULT_INITIAL_BRANCH,
+    DEFAULT_MOVE_MESSAGE_TEMPLATE, DEFAULT_REMOTE_NAME_TEMPLATE, DEFAULT_STAGING_NAME,
+    MANIFEST_BRANCH_PATTERN,
+};
+use trenza::progress::{current_repo, MergeEvent, ProgressHandler};
+use trenza::report::RepoStatus;
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Join repositories to one monorepo.
 struct Trenza {
+    /// only log errors and don't print progress
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+
+    /// log more details, repeat for even more; RUST_LOG takes precedence
+    #[argh(switch, short = 'v')]
+    verbose: u8,
+
+    /// format of log records and progress on stderr: text (default) or json, one object per line
+    #[argh(option, default = "LogFormat::Text")]
+    log_format: LogFormat,
+
     #[argh(subcommand)]
     cmd: Commands,
 }
 
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum LogFormat {
+    /// Human-readable lines as printed by env_logger.
+    Text,
+    /// A JSON object per line with the level, message and repository if there is one.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "invalid log format {s}, expected one of text, json"
+            )),
+        }
+    }
+}
+
+/// Log target of progress events with [`LogFormat::Json`], logged at info level.
+const PROGRESS_LOG_TARGET: &str = "trenza::progress";
+
+// argh cannot parse boxed subcommands, and the commands are only parsed once.
+#[allow(clippy::large_enum_variant)]
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 enum Commands {
     Join(JoinRepoArgs),
+    List(ListArgs),
+    Check(CheckArgs),
+    Undo(UndoArgs),
+    Split(SplitArgs),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "join")]
 /// join repositories
 struct JoinRepoArgs {
-    /// root directory below which to join git repositories
+    /// root directory below which to join git repositories, required unless set in the config
+    #[argh(positional)]
+    root: Option<String>,
+
+    /// TOML file with settings for the join, overridden by command line flags
+    #[argh(option)]
+    config: Option<PathBuf>,
+
+    /// glob pattern below the root matching repositories, e.g. **/*.git for bare ones
+    #[argh(option, default = "DEFAULT_DISCOVERY_PATTERN.to_owned()")]
+    pattern: String,
+
+    /// maximum depth below the root at which to consider repositories
+    #[argh(option)]
+    depth: Option<usize>,
+
+    /// also consider repositories nested inside other repositories
+    #[argh(switch)]
+    allow_nested: bool,
+
+    /// also consider repositories reached through symbolic links
+    #[argh(switch)]
+    follow_symlinks: bool,
+
+    /// only consider repositories whose path relative to the root matches this glob, repeatable
+    #[argh(option)]
+    include: Vec<String>,
+
+    /// leave out repositories whose path relative to the root matches this glob, repeatable
+    #[argh(option)]
+    exclude: Vec<String>,
+
+    /// leave out repositories whose path relative to the root matches this regex, repeatable
+    #[argh(option)]
+    exclude_regex: Vec<String>,
+
+    /// file with .gitignore syntax listing repositories to leave out, defaults to .trenzaignore
+    /// in the root
+    #[argh(option)]
+    ignore_file: Option<PathBuf>,
+
+    /// order of the repositories: lexical (default), natural or discovery
+    #[argh(option, default = "RepoOrder::Lexical")]
+    sort: RepoOrder,
+
+    /// suffix to append to the new joined repository, defaults to _joined
+    #[argh(option)]
+    suffix: Option<String>,
+
+    /// path of the new joined repository, overrides the suffix
+    #[argh(option)]
+    target: Option<String>,
+
+    /// path of an existing repository to join the repositories into, instead of a new one
+    #[argh(option)]
+    into: Option<String>,
+
+    /// create the joined repository as a bare repository, merging in a temporary one next to it
+    #[argh(switch)]
+    bare_target: bool,
+
+    /// common subdirectory below which to place all joined repositories
+    #[argh(option)]
+    target_prefix: Option<String>,
+
+    /// number of leading path components of repositories to drop from their subdirectory
+    #[argh(option, default = "0")]
+    strip_prefix: usize,
+
+    /// how to handle repositories placed in the same subdirectory: error (default), suffix to
+    /// append a counter, or skip
+    #[argh(option, default = "OnCollision::Error")]
+    on_collision: OnCollision,
+
+    /// file mapping repository paths relative to the root to target subdirectories
+    #[argh(option)]
+    path_map: Option<PathBuf>,
+
+    /// branch to use for every repository
+    #[argh(option)]
+    branch: Option<String>,
+
+    /// comma-separated branches tried in order for repositories without the requested branch
+    #[argh(option)]
+    branch_fallbacks: Option<String>,
+
+    /// regex finding the manifest branch in `git branch -r`, with one capture group for it
+    #[argh(option, default = "MANIFEST_BRANCH_PATTERN.to_owned()")]
+    manifest_pattern: String,
+
+    /// use the default branch of origin if no manifest branch is found
+    #[argh(switch)]
+    fallback_default_branch: bool,
+
+    /// file mapping repository paths relative to the root to branches
+    #[argh(option)]
+    manifest: Option<PathBuf>,
+
+    /// number of source repositories to fetch concurrently
+    #[argh(option, default = "1")]
+    jobs: usize,
+
+    /// number of times a failed fetch is retried
+    #[argh(option, default = "0")]
+    fetch_retries: u32,
+
+    /// seconds to wait before the first retry of a failed fetch, doubled for further retries
+    #[argh(option, default = "1")]
+    retry_delay: u64,
+
+    /// only fetch the latest commit of every repository, dropping older history
+    #[argh(switch)]
+    shallow: bool,
+
+    /// number of commits of history to fetch from every repository, implies --shallow
+    #[argh(option)]
+    depth_history: Option<u32>,
+
+    /// name of the remote added for every repository, {{repo}} is replaced by the repository
+    #[argh(option, default = "DEFAULT_REMOTE_NAME_TEMPLATE.to_owned()")]
+    remote_name_template: String,
+
+    /// fetch the tags of every repository prefixed with its name
+    #[argh(switch)]
+    preserve_tags: bool,
+
+    /// refspec to fetch from every repository instead of all its branches, {{branch}} and
+    /// {{remote}} are replaced, e.g. +refs/heads/{{branch}}:refs/remotes/{{remote}}/{{branch}}
+    #[argh(option)]
+    fetch_refspec: Option<String>,
+
+    /// message of the commit moving repository content, {{repo}} is replaced by the repository
+    #[argh(option, default = "DEFAULT_MOVE_MESSAGE_TEMPLATE.to_owned()")]
+    move_message_template: String,
+
+    /// message of the commit merging a repository, {{repo}} and {{branch}} are replaced,
+    /// defaults to "Merge <remote>/<branch> into <subdirectory>"
+    #[argh(option)]
+    merge_message_template: Option<String>,
+
+    /// name of the top-level directory where repository content is staged while it is moved
+    #[argh(option, default = "DEFAULT_STAGING_NAME.to_owned()")]
+    staging_name: String,
+
+    /// move the content to its subdirectory in a single commit instead of amending a staged move
+    #[argh(switch)]
+    no_amend: bool,
+
+    /// line endings to convert the text files of every repository to: lf, crlf or none (default)
+    #[argh(option, default = "EolMode::None")]
+    normalize_eol: EolMode,
+
+    /// keep top-level entries of every repository matching this glob at the root, repeatable
+    #[argh(option)]
+    keep_at_root: Vec<String>,
+
+    /// how to handle merge conflicts: abort (default), theirs or skip
+    #[argh(option, default = "OnConflict::Abort")]
+    on_conflict: OnConflict,
+
+    /// how repository content is placed in its subdirectory: move (default), subtree or filter-repo
+    #[argh(option, default = "MergeStrategy::Move")]
+    strategy: MergeStrategy,
+
+    /// add every repository as a single commit with its current tree instead of its history
+    #[argh(switch)]
+    squash_history: bool,
+
+    /// merge the content of all repositories into the root instead of their subdirectories,
+    /// resolving overlapping paths with --on-conflict
+    #[argh(switch)]
+    flatten: bool,
+
+    /// combine the .gitignore files of all repositories in a .gitignore at the root
+    #[argh(switch)]
+    merge_gitignore: bool,
+
+    /// combine the .gitattributes files of all repositories, scoped to their subdirectory, at the root
+    #[argh(switch)]
+    rewrite_gitattributes: bool,
+
+    /// remove directories without tracked files and their placeholders like .gitkeep after the join
+    #[argh(switch)]
+    prune_empty_dirs: bool,
+
+    /// move top-level files matching this glob to the root if they are identical in all
+    /// repositories, repeatable
+    #[argh(option)]
+    dedup_root_files: Vec<String>,
+
+    /// shell command run in the joined repository after all merges, the merged repositories
+    /// are passed in TRENZA_MERGED_REPOS, one per line
+    #[argh(option)]
+    post_merge_hook: Option<String>,
+
+    /// shell command run in every source repository after checking out its branch, the
+    /// repository and branch are passed in TRENZA_REPO and TRENZA_BRANCH
+    #[argh(option)]
+    pre_merge_hook: Option<String>,
+
+    /// how submodules of repositories are handled: keep (default), ignore or inline
+    #[argh(option, default = "SubmoduleMode::Keep")]
+    submodules: SubmoduleMode,
+
+    /// how git LFS content of repositories is handled: keep (default) pointers or fetch content
+    #[argh(option, default = "LfsMode::Keep")]
+    lfs: LfsMode,
+
+    /// skip repositories which fail to merge instead of stopping, exit with an error at the end
+    #[argh(switch)]
+    continue_on_error: bool,
+
+    /// keep the remotes of the source repositories in the joined repository
+    #[argh(switch)]
+    keep_remotes: bool,
+
+    /// add the remotes of the source repositories with paths relative to the joined repository
+    #[argh(switch)]
+    relative_remotes: bool,
+
+    /// replace an existing target repository created by a previous run
+    #[argh(switch)]
+    force: bool,
+
+    /// stash uncommitted changes in source repositories and restore them after the join
+    #[argh(switch)]
+    stash_dirty: bool,
+
+    /// fail on source repositories without commits instead of skipping them
+    #[argh(switch)]
+    fail_on_empty: bool,
+
+    /// warn about source repositories whose objects take more than this many bytes
+    #[argh(option)]
+    max_repo_size: Option<u64>,
+
+    /// fail the checks for source repositories above --max-repo-size instead of warning
+    #[argh(switch)]
+    strict: bool,
+
+    /// check that no two repositories would add the same path, e.g. in nested subdirectories
+    #[argh(switch)]
+    check_conflicts: bool,
+
+    /// check after every merge that the subdirectory contains exactly the merged tree
+    #[argh(switch)]
+    verify: bool,
+
+    /// add a note naming the source repository to the commit merging it
+    #[argh(switch)]
+    annotate_commits: bool,
+
+    /// skip checking git, the source repositories and their branches before joining
+    #[argh(switch)]
+    skip_preflight: bool,
+
+    /// don't start a new joined repository with an empty initial commit
+    #[argh(switch)]
+    no_seed_commit: bool,
+
+    /// name of the branch of a new joined repository, defaults to main
+    #[argh(option, default = "DEFAULT_INITIAL_BRANCH.to_owned()")]
+    initial_branch: String,
+
+    /// merge from the remotes already fetched into the target, requires --into or --resume
+    #[argh(switch)]
+    no_fetch: bool,
+
+    /// continue an interrupted join into the existing target repository
+    #[argh(switch)]
+    resume: bool,
+
+    /// seconds after which no further repository is merged, the join then exits with status 124
+    /// and can be continued with --resume
+    #[argh(option)]
+    max_duration: Option<u64>,
+
+    /// path of the git executable
+    #[argh(option, default = "PathBuf::from(\"git\")")]
+    git_binary: PathBuf,
+
+    /// seconds after which a single git command is aborted
+    #[argh(option)]
+    timeout: Option<u64>,
+
+    /// identity for the commits created by trenza as "Name <email>", defaults to the one
+    /// configured in git
+    #[argh(option)]
+    commit_author: Option<Identity>,
+
+    /// sign the commits created by trenza, including merge commits
+    #[argh(switch)]
+    sign: bool,
+
+    /// key to sign commits with, implies --sign
+    #[argh(option)]
+    sign_key: Option<String>,
+
+    /// stage the repositories as a merge to review and commit instead of committing them
+    #[argh(switch)]
+    no_commit: bool,
+
+    /// confirm that --no-commit stages several repositories in a single merge
+    #[argh(switch)]
+    single_step: bool,
+
+    /// print the merge plan without touching any git repository
+    #[argh(switch)]
+    dry_run: bool,
+
+    /// format of the summary printed to stdout: text (default) or json
+    #[argh(option, default = "OutputFormat::Text")]
+    output: OutputFormat,
+
+    /// file to append a JSON line to for every finished repository
+    #[argh(option)]
+    progress_file: Option<PathBuf>,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum OutputFormat {
+    /// Only the merge plan of dry runs, one line per repository.
+    Text,
+    /// The full merge report.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "invalid output format {s}, expected one of text, json"
+            )),
+        }
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "list")]
+/// list repositories that would be joined
+struct ListArgs {
+    /// root directory below which to search for git repositories
     #[argh(positional)]
     root: String,
 
-    /// suffix to append to the new joined repository
-    #[argh(option, default = "String::from(\"_joined\")")]
-    suffix: String,
+    /// glob pattern below the root matching repositories, e.g. **/*.git for bare ones
+    #[argh(option, default = "DEFAULT_DISCOVERY_PATTERN.to_owned()")]
+    pattern: String,
+
+    /// maximum depth below the root at which to consider repositories
+    #[argh(option)]
+    depth: Option<usize>,
+
+    /// also consider repositories nested inside other repositories
+    #[argh(switch)]
+    allow_nested: bool,
+
+    /// also consider repositories reached through symbolic links
+    #[argh(switch)]
+    follow_symlinks: bool,
+
+    /// only consider repositories whose path relative to the root matches this glob, repeatable
+    #[argh(option)]
+    include: Vec<String>,
+
+    /// leave out repositories whose path relative to the root matches this glob, repeatable
+    #[argh(option)]
+    exclude: Vec<String>,
+
+    /// leave out repositories whose path relative to the root matches this regex, repeatable
+    #[argh(option)]
+    exclude_regex: Vec<String>,
+
+    /// file with .gitignore syntax listing repositories to leave out, defaults to .trenzaignore
+    /// in the root
+    #[argh(option)]
+    ignore_file: Option<PathBuf>,
+
+    /// order of the repositories: lexical (default), natural or discovery
+    #[argh(option, default = "RepoOrder::Lexical")]
+    sort: RepoOrder,
+
+    /// print absolute paths instead of paths relative to the root
+    #[argh(switch)]
+    absolute: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "check")]
+/// check repositories and their branches before joining them, without changing anything
+struct CheckArgs {
+    /// root directory below which to check git repositories, required unless set in the config
+    #[argh(positional)]
+    root: Option<String>,
+
+    /// TOML file with settings for the join, overridden by command line flags
+    #[argh(option)]
+    config: Option<PathBuf>,
+
+    /// glob pattern below the root matching repositories, e.g. **/*.git for bare ones
+    #[argh(option, default = "DEFAULT_DISCOVERY_PATTERN.to_owned()")]
+    pattern: String,
+
+    /// maximum depth below the root at which to consider repositories
+    #[argh(option)]
+    depth: Option<usize>,
+
+    /// also consider repositories nested inside other repositories
+    #[argh(switch)]
+    allow_nested: bool,
+
+    /// also consider repositories reached through symbolic links
+    #[argh(switch)]
+    follow_symlinks: bool,
+
+    /// only consider repositories whose path relative to the root matches this glob, repeatable
+    #[argh(option)]
+    include: Vec<String>,
+
+    /// leave out repositories whose path relative to the root matches this glob, repeatable
+    #[argh(option)]
+    exclude: Vec<String>,
+
+    /// leave out repositories whose path relative to the root matches this regex, repeatable
+    #[argh(option)]
+    exclude_regex: Vec<String>,
+
+    /// file with .gitignore syntax listing repositories to leave out, defaults to .trenzaignore
+    /// in the root
+    #[argh(option)]
+    ignore_file: Option<PathBuf>,
+
+    /// common subdirectory below which to place all joined repositories
+    #[argh(option)]
+    target_prefix: Option<String>,
+
+    /// number of leading path components of repositories to drop from their subdirectory
+    #[argh(option, default = "0")]
+    strip_prefix: usize,
+
+    /// how to handle repositories placed in the same subdirectory: error (default), suffix to
+    /// append a counter, or skip
+    #[argh(option, default = "OnCollision::Error")]
+    on_collision: OnCollision,
+
+    /// file mapping repository paths relative to the root to target subdirectories
+    #[argh(option)]
+    path_map: Option<PathBuf>,
 
     /// branch to use for every repository
     #[argh(option)]
     branch: Option<String>,
+
+    /// comma-separated branches tried in order for repositories without the requested branch
+    #[argh(option)]
+    branch_fallbacks: Option<String>,
+
+    /// regex finding the manifest branch in `git branch -r`, with one capture group for it
+    #[argh(option, default = "MANIFEST_BRANCH_PATTERN.to_owned()")]
+    manifest_pattern: String,
+
+    /// use the default branch of origin if no manifest branch is found
+    #[argh(switch)]
+    fallback_default_branch: bool,
+
+    /// file mapping repository paths relative to the root to branches
+    #[argh(option)]
+    manifest: Option<PathBuf>,
+
+    /// how repository content will be placed in its subdirectory: move (default), subtree or
+    /// filter-repo
+    #[argh(option, default = "MergeStrategy::Move")]
+    strategy: MergeStrategy,
+
+    /// accept uncommitted changes in source repositories, as they will be stashed by the join
+    #[argh(switch)]
+    stash_dirty: bool,
+
+    /// fail on source repositories without commits instead of skipping them
+    #[argh(switch)]
+    fail_on_empty: bool,
+
+    /// warn about source repositories whose objects take more than this many bytes
+    #[argh(option)]
+    max_repo_size: Option<u64>,
+
+    /// fail the checks for source repositories above --max-repo-size instead of warning
+    #[argh(switch)]
+    strict: bool,
+
+    /// check that no two repositories would add the same path, e.g. in nested subdirectories
+    #[argh(switch)]
+    check_conflicts: bool,
+
+    /// path of the git executable
+    #[argh(option, default = "PathBuf::from(\"git\")")]
+    git_binary: PathBuf,
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "undo")]
+/// remove a joined repository created by trenza
+struct UndoArgs {
+    /// path of the joined repository to remove
+    #[argh(positional)]
+    target: String,
+
+    /// message template used for the join, {{repo}} is replaced by the repository
+    #[argh(option, default = "DEFAULT_MOVE_MESSAGE_TEMPLATE.to_owned()")]
+    move_message_template: String,
+
+    /// remove the target even if it does not look like it was created by trenza
+    #[argh(switch)]
+    force: bool,
+
+    /// path of the git executable
+    #[argh(option, default = "PathBuf::from(\"git\")")]
+    git_binary: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "split")]
+/// split a subdirectory of a joined repository out into a new repository
+struct SplitArgs {
+    /// path of the joined repository
+    #[argh(positional)]
+    target: String,
+
+    /// subdirectory to split out
+    #[argh(positional)]
+    subdir: String,
+
+    /// path of the new repository, must not exist
+    #[argh(option)]
+    output: String,
+
+    /// branch of the new repository
+    #[argh(option, default = "DEFAULT_INITIAL_BRANCH.to_owned()")]
+    initial_branch: String,
+
+    /// path of the git executable
+    #[argh(option, default = "PathBuf::from(\"git\")")]
+    git_binary: PathBuf,
+}
+
+/// Exit status of a join stopped by `--max-duration`, so that scheduled jobs can tell it apart
+/// from failures and resume it.
+const DEADLINE_EXCEEDED_EXIT_CODE: i32 = 124;
+
+/// Exit status if the git executable does not exist, as used by shells for unknown commands.
+const GIT_NOT_FOUND_EXIT_CODE: i32 = 127;
+
 fn main() -> Result<()> {
-    env_logger::init();
+    let res = run(argh::from_env());
+    if let Err(err) = &res {
+        if err.downcast_ref::<DeadlineExceeded>().is_some() {
+            eprintln!("Error: {err:?}");
+            process::exit(DEADLINE_EXCEEDED_EXIT_CODE);
+        }
+        if err.downcast_ref::<GitNotFound>().is_some() {
+            eprintln!("Error: {err:?}");
+            process::exit(GIT_NOT_FOUND_EXIT_CODE);
+        }
+    }
+
+    res
+}
 
-    let cli: Trenza = argh::from_env();
+fn run(cli: Trenza) -> Result<()> {
+    if cli.quiet && cli.verbose > 0 {
+        bail!("--quiet and --verbose cannot be combined");
+    }
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => "error",
+        (false, 0) => "warn",
+        (false, 1) => "debug",
+        (false, _) => "trace",
+    };
+    // Progress is printed as log records in JSON, so that the output stays easy to parse.
+    let filter = match cli.log_format {
+        LogFormat::Json if !cli.quiet => format!("{level},{PROGRESS_LOG_TARGET}=info"),
+        _ => level.to_owned(),
+    };
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter));
+    if cli.log_format == LogFormat::Json {
+        logger.format(|buf, record| {
+            let mut line = serde_json::json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            if let Some(repo) = current_repo() {
+                line["repo"] = repo.into();
+            }
+            writeln!(buf, "{line}")
+        });
+    }
+    logger.init();
 
     match cli.cmd {
         Commands::Join(args) => {
-            merge_repositories(&args.root, &args.suffix, args.branch.as_deref())
+            if args.into.is_some() && args.target.is_some() {
+                bail!("--into and --target cannot be combined");
+            }
+
+            let config = match &args.config {
+                Some(path) => Config::load(path)?,
+                None => Config::default(),
+            };
+            let Some(root) = args.root.or(config.root.clone()) else {
+                bail!("no root given, pass it as argument or set it in the config");
+            };
+
+            let (path_map, branch_map) =
+                repo_maps(&config, args.path_map.as_deref(), args.manifest.as_deref())?;
+            let extra_branches = config.extra_branches();
+            // Opened up front so that an unusable path fails before any work is done.
+            let progress_file = args
+                .progress_file
+                .as_deref()
+                .map(|path| {
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .with_context(|| format!("failed to open progress file {}", path.display()))
+                })
+                .transpose()?;
+
+            let options = MergeOptions {
+                git: GitRunner::new(args.git_binary)
+                    .with_timeout(args.timeout.map(Duration::from_secs))
+                    .with_identity(args.commit_author)
+                    .with_signing(
+                        (args.sign || args.sign_key.is_some())
+                            .then_some(Signing { key: args.sign_key }),
+                    ),
+                discovery: DiscoveryOptions {
+                    pattern: args.pattern,
+                    max_depth: args.depth,
+                    allow_nested: args.allow_nested,
+                    include: or_config(args.include, config.include),
+                    exclude: or_config(args.exclude, config.exclude),
+                    exclude_regex: args.exclude_regex,
+                    ignore_file: args.ignore_file,
+                    sort: args.sort,
+                    follow_symlinks: args.follow_symlinks,
+                },
+                joined_suffix: args
+                    .suffix
+                    .or(config.suffix)
+                    .unwrap_or_else(|| "_joined".to_owned()),
+                into_existing: args.into.is_some(),
+                bare_target: args.bare_target,
+                seed_commit: !args.no_seed_commit,
+                initial_branch: args.initial_branch,
+                merge_gitignore: args.merge_gitignore,
+                rewrite_gitattributes: args.rewrite_gitattributes,
+                prune_empty_dirs: args.prune_empty_dirs,
+                dedup_root_files: args.dedup_root_files,
+                post_merge_hook: args.post_merge_hook,
+                pre_merge_hook: args.pre_merge_hook,
+                submodules: args.submodules,
+                continue_on_error: args.continue_on_error,
+                lfs: args.lfs,
+                target: args.into.or(args.target).or(config.target),
+                target_prefix: args.target_prefix,
+                strip_prefix: args.strip_prefix,
+                on_collision: args.on_collision,
+                path_map,
+                branch: args.branch.or(config.branch),
+                branch_fallbacks: split_list(args.branch_fallbacks.as_deref()),
+                manifest_pattern: args.manifest_pattern,
+                fallback_default_branch: args.fallback_default_branch,
+                branch_map,
+                extra_branches,
+                jobs: args.jobs,
+                fetch_retries: args.fetch_retries,
+                retry_delay: Duration::from_secs(args.retry_delay),
+                fetch_depth: args.depth_history.or(args.shallow.then_some(1)),
+                remote_name_template: args.remote_name_template,
+                preserve_tags: args.preserve_tags,
+                fetch_refspec: args.fetch_refspec,
+                move_message_template: args.move_message_template,
+                merge_message_template: args.merge_message_template,
+                staging_name: args.staging_name,
+                no_amend: args.no_amend,
+                normalize_eol: args.normalize_eol,
+                keep_at_root: args.keep_at_root,
+                on_conflict: args.on_conflict,
+                strategy: args.strategy,
+                squash_history: args.squash_history,
+                flatten: args.flatten,
+                keep_remotes: args.keep_remotes,
+                relative_remotes: args.relative_remotes,
+                force: args.force,
+                stash_dirty: args.stash_dirty,
+                fail_on_empty: args.fail_on_empty,
+                max_repo_size: args.max_repo_size,
+                strict: args.strict,
+                check_conflicts: args.check_conflicts,
+                verify: args.verify,
+                annotate_commits: args.annotate_commits,
+                skip_preflight: args.skip_preflight,
+                resume: args.resume,
+                max_duration: args.max_duration.map(Duration::from_secs),
+                no_fetch: args.no_fetch,
+                no_commit: args.no_commit,
+                single_step: args.single_step,
+                dry_run: args.dry_run,
+                on_progress: progress_handler(cli.quiet, cli.log_format, progress_file),
+            };
+
+            check_git_installed(&options.git)?;
+            let report = merge_repositories(&root, &options)?;
+
+            match args.output {
+                OutputFormat::Text => {
+                    for repo in report.repos.iter() {
+                        if repo.status == RepoStatus::Planned {
+                            // Plan line: source repository, branch to merge and target subdirectory.
+                            println!(
+                                "{}\t{}\t{}",
+                                repo.source.display(),
+                                repo.branch,
+                                repo.subdir
+                            );
+                        }
+                    }
+                }
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+            }
+
+            let failed: Vec<_> = report
+                .repos
+                .iter()
+                .filter(|repo| repo.status == RepoStatus::Failed)
+                .map(|repo| repo.source.display().to_string())
+                .collect();
+            if !failed.is_empty() {
+                bail!(
+                    "{} of {} repositories failed: {}",
+                    failed.len(),
+                    report.repos.len(),
+                    failed.join(", ")
+                );
+            }
+
+            Ok(())
         }
+        Commands::List(args) => {
+            let options = DiscoveryOptions {
+                pattern: args.pattern,
+                max_depth: args.depth,
+                allow_nested: args.allow_nested,
+                include: args.include,
+                exclude: args.exclude,
+                exclude_regex: args.exclude_regex,
+                ignore_file: args.ignore_file,
+                sort: args.sort,
+                follow_symlinks: args.follow_symlinks,
+            };
+
+            for repo_path in discover_repositories(&args.root, &options)? {
+                if args.absolute {
+                    println!("{}", fs::canonicalize(&repo_path)?.display());
+                } else {
+                    println!("{}", repo_path.strip_prefix(&args.root)?.display());
+                }
+            }
+
+            Ok(())
+        }
+        Commands::Check(args) => {
+            let config = match &args.config {
+                Some(path) => Config::load(path)?,
+                None => Config::default(),
+            };
+            let Some(root) = args.root.or(config.root.clone()) else {
+                bail!("no root given, pass it as argument or set it in the config");
+            };
+
+            let (path_map, branch_map) =
+                repo_maps(&config, args.path_map.as_deref(), args.manifest.as_deref())?;
+            let options = MergeOptions {
+                git: GitRunner::new(args.git_binary),
+                discovery: DiscoveryOptions {
+                    pattern: args.pattern,
+                    max_depth: args.depth,
+                    allow_nested: args.allow_nested,
+                    include: or_config(args.include, config.include.clone()),
+                    exclude: or_config(args.exclude, config.exclude.clone()),
+                    exclude_regex: args.exclude_regex,
+                    ignore_file: args.ignore_file,
+                    follow_symlinks: args.follow_symlinks,
+                    ..Default::default()
+                },
+                target_prefix: args.target_prefix,
+                strip_prefix: args.strip_prefix,
+                on_collision: args.on_collision,
+                path_map,
+                branch: args.branch.or(config.branch.clone()),
+                branch_fallbacks: split_list(args.branch_fallbacks.as_deref()),
+                manifest_pattern: args.manifest_pattern,
+                fallback_default_branch: args.fallback_default_branch,
+                branch_map,
+                extra_branches: config.extra_branches(),
+                strategy: args.strategy,
+                stash_dirty: args.stash_dirty,
+                fail_on_empty: args.fail_on_empty,
+                max_repo_size: args.max_repo_size,
+                strict: args.strict,
+                check_conflicts: args.check_conflicts,
+                ..Default::default()
+            };
+
+            check_git_installed(&options.git)?;
+            let checks = check_repositories(&root, &options)?;
+            // Status table: repository, branch to merge, target subdirectory and problems.
+            for check in checks.iter() {
+                let status = if !check.problems.is_empty() {
+                    check.problems.join("; ")
+                } else if check.empty {
+                    "skipped, no commits".to_owned()
+                } else {
+                    "ok".to_owned()
+                };
+                println!(
+                    "{}\t{}\t{}\t{status}",
+                    check.repo,
+                    check.branch.as_deref().unwrap_or("-"),
+                    check.subdir
+                );
+            }
+
+            let failed = checks
+                .iter()
+                .filter(|check| !check.problems.is_empty())
+                .count();
+            if failed > 0 {
+                bail!("{failed} of {} checks failed", checks.len());
+            }
+
+            Ok(())
+        }
+        Commands::Undo(args) => {
+            let options = MergeOptions {
+                git: GitRunner::new(args.git_binary),
+                move_message_template: args.move_message_template,
+                force: args.force,
+                ..Default::default()
+            };
+
+            check_git_installed(&options.git)?;
+            undo_merge(&args.target, &options)
+        }
+        Commands::Split(args) => {
+            let options = MergeOptions {
+                git: GitRunner::new(args.git_binary),
+                initial_branch: args.initial_branch,
+                ..Default::default()
+            };
+
+            check_git_installed(&options.git)?;
+            split_subdir(&args.target, &args.subdir, &args.output, &options)
+        }
+    }
+}
+
+/// Load the path and branch maps of the config, extended by the entries of the map files.
+fn repo_maps(
+    config: &Config,
+    path_map_file: Option<&Path>,
+    branch_map_file: Option<&Path>,
+) -> Result<(HashMap<String, String>, HashMap<String, String>)> {
+    // Entries of the map files take precedence over the config.
+    let mut path_map = config.path_map();
+    if let Some(path) = path_map_file {
+        path_map.extend(load_path_map(path)?);
+    }
+    let mut branch_map = config.branch_map();
+    if let Some(path) = branch_map_file {
+        branch_map.extend(load_branch_map(path)?);
+    }
+
+    Ok((path_map, branch_map))
+}
+
+/// Split a comma-separated flag value, leaving out empty entries.
+fn split_list(value: Option<&str>) -> Vec<String> {
+    value
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Use repeatable `flag` values if any were given, the `config` values otherwise.
+fn or_config(flag: Vec<String>, config: Vec<String>) -> Vec<String> {
+    if flag.is_empty() {
+        config
+    } else {
+        flag
+    }
+}
+
+/// Handler printing progress unless `quiet` and writing finished repositories to `progress_file`.
+fn progress_handler(
+    quiet: bool,
+    log_format: LogFormat,
+    progress_file: Option<File>,
+) -> Option<ProgressHandler> {
+    if quiet && progress_file.is_none() {
+        return None;
+    }
+
+    let progress_file = progress_file.map(Mutex::new);
+    Some(ProgressHandler::new(move |event| {
+        if !quiet {
+            print_progress(event, log_format);
+        }
+        if let (Some(file), MergeEvent::RepoFinished(progress, status)) = (&progress_file, event) {
+            let line = serde_json::json!({
+                "repo": progress.repo,
+                "index": progress.index,
+                "total": progress.total,
+                "status": status,
+            });
+            // Every line is flushed right away so that other processes can follow the file.
+            let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+            if let Err(err) = writeln!(file, "{line}").and_then(|_| file.flush()) {
+                log::warn!("Failed to write progress file: {err}");
+            }
+        }
+    }))
+}
+
+/// Print fetches and merges to stderr, keeping stdout free for the dry-run plan.
+fn print_progress(event: &MergeEvent, log_format: LogFormat) {
+    if !matches!(event, MergeEvent::Fetched(_) | MergeEvent::Merged(_)) {
+        return;
+    }
+    match log_format {
+        LogFormat::Text => eprintln!("{event}"),
+        LogFormat::Json => log::info!(target: PROGRESS_LOG_TARGET, "{event}"),
     }
 }
@@ -0,0 +1,313 @@
+use std::{
+    ffi::OsStr,
+    fmt,
+    io::{self, Read},
+    ops::Deref,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Output, Stdio},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Interval in which running commands are checked for completion when a timeout is set.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Maximum number of bytes of stdout shown in the message of a [`GitError`].
+const MAX_ERROR_STDOUT_LEN: usize = 500;
+
+/// Builds git commands using a configurable git executable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitRunner {
+    binary: PathBuf,
+    timeout: Option<Duration>,
+    identity: Option<Identity>,
+    signing: Option<Signing>,
+}
+
+impl GitRunner {
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: binary.into(),
+            timeout: None,
+            identity: None,
+            signing: None,
+        }
+    }
+
+    /// Kill git commands which take longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Create commits as `identity` instead of the identity from the git configuration.
+    pub fn with_identity(mut self, identity: Option<Identity>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Sign all commits, including merge commits, as configured by `signing`.
+    pub fn with_signing(mut self, signing: Option<Signing>) -> Self {
+        self.signing = signing;
+        self
+    }
+
+    /// Path of the git executable.
+    pub fn binary(&self) -> &Path {
+        &self.binary
+    }
+
+    /// Create a git command running in directory `dir`.
+    pub fn command(&self, dir: impl AsRef<Path>) -> GitCommand {
+        let mut command = Command::new(&self.binary);
+        command.current_dir(dir);
+
+        let mut config = Vec::new();
+        if let Some(Identity { name, email }) = &self.identity {
+            config.push(format!("user.name={name}"));
+            config.push(format!("user.email={email}"));
+        }
+        if let Some(Signing { key }) = &self.signing {
+            config.push("commit.gpgSign=true".to_owned());
+            if let Some(key) = key {
+                config.push(format!("user.signingKey={key}"));
+            }
+        }
+        for value in config.iter() {
+            command.args(["-c", value]);
+        }
+        let config_args = 2 * config.len();
+
+        GitCommand {
+            command,
+            timeout: self.timeout,
+            config_args,
+        }
+    }
+}
+
+impl Default for GitRunner {
+    fn default() -> Self {
+        Self::new("git")
+    }
+}
+
+/// Author and committer of commits, parsed from `Name <email>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    pub email: String,
+}
+
+impl FromStr for Identity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid identity {s:?}, expected \"Name <email>\"");
+        let (name, email) = s.trim().split_once('<').ok_or_else(invalid)?;
+        let email = email.strip_suffix('>').ok_or_else(invalid)?;
+        let name = name.trim();
+        if name.is_empty() || email.is_empty() || email.contains(['<', '>']) {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            email: email.to_owned(),
+        })
+    }
+}
+
+/// How commits are signed, with `commit.gpgSign` and the signing format configured in git.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Signing {
+    /// Key to sign with instead of `user.signingKey` or the default key for the committer.
+    pub key: Option<String>,
+}
+
+/// Git command which is killed if it exceeds the timeout of its runner.
+#[derive(Debug)]
+pub struct GitCommand {
+    command: Command,
+    timeout: Option<Duration>,
+    /// Number of leading `-c` arguments added by the runner.
+    config_args: usize,
+}
+
+impl GitCommand {
+    /// Set a configuration value for this command only, has to come before any argument.
+    pub fn config(&mut self, key: &str, value: &str) -> &mut Self {
+        self.command.args(["-c", &format!("{key}={value}")]);
+        self.config_args += 2;
+        self
+    }
+
+    pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        self.command.arg(arg);
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.command.args(args);
+        self
+    }
+
+    /// Run the command to completion and collect its output like [`Command::output`].
+    ///
+    /// Fails with [`io::ErrorKind::TimedOut`] if the command exceeds the timeout.
+    pub fn output(&mut self) -> io::Result<GitOutput> {
+        let args = self
+            .command
+            .get_args()
+            .skip(self.config_args)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+
+        Ok(GitOutput {
+            args,
+            output: self.run()?,
+        })
+    }
+
+    fn run(&mut self) -> io::Result<Output> {
+        let Some(timeout) = self.timeout else {
+            return self.command.output();
+        };
+
+        let mut child = self
+            .command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Pipes are drained concurrently so that the child cannot block on a full pipe.
+        let stdout = read_to_end_in_background(child.stdout.take());
+        let stderr = read_to_end_in_background(child.stderr.take());
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("git command timed out after {}s", timeout.as_secs_f64()),
+                ));
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().expect("stdout reader panicked")?,
+            stderr: stderr.join().expect("stderr reader panicked")?,
+        })
+    }
+}
+
+/// Output of a git command together with the arguments it was run with.
+#[derive(Debug)]
+pub struct GitOutput {
+    pub args: Vec<String>,
+    pub output: Output,
+}
+
+impl GitOutput {
+    /// Fail with a [`GitError`] if the command exited with a non-zero status.
+    pub fn check(self) -> Result<Output, GitError> {
+        if self.output.status.success() {
+            return Ok(self.output);
+        }
+
+        Err(GitError {
+            args: self.args,
+            status: self.output.status,
+            stdout: String::from_utf8_lossy(&self.output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&self.output.stderr).into_owned(),
+        })
+    }
+}
+
+impl Deref for GitOutput {
+    type Target = Output;
+
+    fn deref(&self) -> &Output {
+        &self.output
+    }
+}
+
+/// Git command which exited with a non-zero status.
+///
+/// Errors of failed git commands can be downcast to this type to inspect the full output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitError {
+    pub args: Vec<String>,
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl fmt::Display for GitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let subcommand = self.args.first().map_or("", String::as_str);
+        write!(
+            f,
+            "failed to run git {subcommand} ({}): {}",
+            self.status,
+            self.stderr.trim_end()
+        )?;
+
+        let stdout = self.stdout.trim_end();
+        if !stdout.is_empty() {
+            write!(f, "\nstdout: {}", truncate(stdout, MAX_ERROR_STDOUT_LEN))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl GitError {
+    /// Whether the command failed because a commit could not be signed.
+    pub fn is_signing_failure(&self) -> bool {
+        self.stderr.contains("failed to sign")
+    }
+}
+
+impl std::error::Error for GitError {}
+
+/// Shorten `text` to at most `max_len` bytes on a character boundary.
+fn truncate(text: &str, max_len: usize) -> String {
+    if text.len() <= max_len {
+        return text.to_owned();
+    }
+
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &text[..end])
+}
+
+fn read_to_end_in_background(
+    pipe: Option<impl Read + Send + 'static>,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buf)?;
+        }
+        Ok(buf)
+    })
+}
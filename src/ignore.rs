@@ -0,0 +1,120 @@
+use std::path::Path;
+
+use ::ignore::gitignore::{Gitignore, GitignoreBuilder};
+use anyhow::{Context, Result};
+
+/// Name of the ignore file read from the root of a merge if no other one is configured.
+pub const IGNORE_FILE_NAME: &str = ".trenzaignore";
+
+/// Repositories to leave out, listed in a file with the syntax of `.gitignore`.
+///
+/// Patterns are matched against repository paths relative to the root with the `.gitignore`
+/// semantics of the `ignore` crate: `!` negates a pattern, patterns containing a `/` other than
+/// a trailing one are relative to the root and others match a directory name at any depth. The
+/// last matching pattern wins and a pattern matching a directory applies to all repositories
+/// below it.
+#[derive(Debug, Clone)]
+pub struct IgnoreRules {
+    matcher: Gitignore,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        Self {
+            matcher: Gitignore::empty(),
+        }
+    }
+}
+
+impl IgnoreRules {
+    /// Load the rules of the ignore file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        // Paths are matched relative to the root of the merge, not to the ignore file.
+        let mut builder = GitignoreBuilder::new(".");
+        if let Some(err) = builder.add(path) {
+            return Err(err).with_context(|| format!("invalid ignore file {}", path.display()));
+        }
+
+        Self::build(&builder).with_context(|| format!("invalid ignore file {}", path.display()))
+    }
+
+    /// Parse the rules of an ignore file.
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new(".");
+        for (idx, line) in content.lines().enumerate() {
+            builder
+                .add_line(None, line)
+                .with_context(|| format!("invalid pattern {line} in line {}", idx + 1))?;
+        }
+
+        Self::build(&builder)
+    }
+
+    fn build(builder: &GitignoreBuilder) -> Result<Self> {
+        let matcher = builder.build()?;
+        Ok(Self { matcher })
+    }
+
+    /// Whether the repository at `rel_path`, relative to the root and separated by `/`, is
+    /// ignored.
+    pub fn is_ignored(&self, rel_path: &str) -> bool {
+        // Paths outside of the root cannot be matched.
+        if Path::new(rel_path).has_root() {
+            return false;
+        }
+
+        // Repositories are always directories.
+        self.matcher
+            .matched_path_or_any_parents(rel_path, true)
+            .is_ignore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unanchored_patterns_match_at_any_depth() {
+        let rules = IgnoreRules::parse("*-old\nvendor/\n").unwrap();
+
+        assert!(rules.is_ignored("libs/foo-old"));
+        assert!(rules.is_ignored("foo-old"));
+        assert!(rules.is_ignored("third_party/vendor/bar"));
+        assert!(!rules.is_ignored("libs/foo"));
+    }
+
+    #[test]
+    fn anchored_patterns_match_relative_to_the_root() {
+        let rules = IgnoreRules::parse("/archive\nlibs/*/legacy\n").unwrap();
+
+        assert!(rules.is_ignored("archive"));
+        assert!(rules.is_ignored("archive/foo"));
+        assert!(!rules.is_ignored("libs/archive"));
+        assert!(rules.is_ignored("libs/foo/legacy"));
+        assert!(!rules.is_ignored("libs/foo/bar/legacy"));
+    }
+
+    #[test]
+    fn negated_patterns_re_include_repositories() {
+        let rules = IgnoreRules::parse("archive/*\n!archive/keep\n# comment\n\n").unwrap();
+
+        assert!(rules.is_ignored("archive/old"));
+        assert!(!rules.is_ignored("archive/keep"));
+        assert!(!rules.is_ignored("libs/keep"));
+    }
+
+    #[test]
+    fn last_matching_pattern_wins() {
+        let rules = IgnoreRules::parse("!foo\nfoo\n").unwrap();
+        assert!(rules.is_ignored("foo"));
+
+        let rules = IgnoreRules::parse("foo\n!foo\n").unwrap();
+        assert!(!rules.is_ignored("foo"));
+    }
+
+    #[test]
+    fn missing_ignore_file_fails() {
+        assert!(IgnoreRules::load(Path::new("/nonexistent/.trenzaignore")).is_err());
+    }
+}
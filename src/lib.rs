@@ -1,22 +1,31 @@
-use anyhow::{bail, Context};
+use anyhow::Context;
 
+pub mod git;
+pub mod ignore;
 pub mod merge;
+pub mod progress;
+pub mod report;
+pub mod state;
 
 pub(crate) trait ToAnyhow {
     fn to_anyhow(self) -> anyhow::Result<std::process::Output>;
 }
 
-impl ToAnyhow for Result<std::process::Output, std::io::Error> {
+impl ToAnyhow for Result<git::GitOutput, std::io::Error> {
     fn to_anyhow(self) -> anyhow::Result<std::process::Output> {
         let output = self.with_context(|| "failed to execute process")?;
 
-        if !output.status.success() {
-            bail!(
-                "failed to run command: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
-        }
-
-        Ok(output)
+        output.check().map_err(|err| {
+            let signing_failed = err.is_signing_failure();
+            let err = anyhow::Error::new(err);
+            if signing_failed {
+                err.context(
+                    "failed to sign the commit, check the signing key and that gpg or the \
+                     configured signing program can use it",
+                )
+            } else {
+                err
+            }
+        })
     }
 }
@@ -1,267 +1,6265 @@
 use std::{
-    collections::HashSet,
-    fs,
+    cmp,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fmt, fs, io,
     path::{Path, PathBuf},
     process::Command,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context, Result};
-use glob::glob;
+use glob::{glob, MatchOptions, Pattern};
 use log::{debug, info, warn};
 use regex::Regex;
+use serde::Deserialize;
 
-use crate::ToAnyhow;
+use crate::{
+    git::GitRunner,
+    ignore::{IgnoreRules, IGNORE_FILE_NAME},
+    progress::{MergeEvent, ProgressHandler, RepoProgress, RepoScope},
+    report::{MergeReport, RepoCheck, RepoReport, RepoStatus},
+    state::{CompletedRepo, MergeState, STATE_FILE},
+    ToAnyhow,
+};
+
+/// Default regex pattern to find the branch/tag pointed to from the manifest in `git branch -r`.
+pub const MANIFEST_BRANCH_PATTERN: &str = r"m\/\S* -> (\S*)";
+
+/// Default message of commits moving repository content to its subdirectory.
+pub const DEFAULT_MOVE_MESSAGE_TEMPLATE: &str = "Move {repo} repo contents";
+
+/// Default name of the remote added to the joined repository for every repository.
+pub const DEFAULT_REMOTE_NAME_TEMPLATE: &str = "{repo}";
+
+/// Default name of the branch of a new joined repository.
+pub const DEFAULT_INITIAL_BRANCH: &str = "main";
+
+/// Name of the directory where repository content is staged while it is moved, see
+/// [`MergeOptions::staging_name`].
+pub const DEFAULT_STAGING_NAME: &str = "z_tmp_unique_target_directory_@@@";
+
+/// Environment variable with the merged repositories, one per line, for the post-merge hook.
+pub const MERGED_REPOS_ENV: &str = "TRENZA_MERGED_REPOS";
+
+/// Environment variable with the repository, relative to the root, for the pre-merge hook.
+pub const REPO_ENV: &str = "TRENZA_REPO";
+
+/// Environment variable with the branch to merge for the pre-merge hook.
+pub const BRANCH_ENV: &str = "TRENZA_BRANCH";
+
+/// Message of the empty commit the joined repository starts from.
+///
+/// The subtree and filter-repo strategies need it even without [`MergeOptions::seed_commit`].
+const INITIAL_COMMIT_MESSAGE: &str = "Initialize joined repository";
+
+/// Message of the commit adding the combined `.gitignore` of all joined repositories.
+const GITIGNORE_COMMIT_MESSAGE: &str = "Combine .gitignore files of joined repositories";
+
+/// Message of the commit adding the combined `.gitattributes` of all joined repositories.
+const GITATTRIBUTES_COMMIT_MESSAGE: &str = "Combine .gitattributes files of joined repositories";
+
+/// Message of the commit moving identical top-level files of joined repositories to the root.
+const DEDUP_COMMIT_MESSAGE: &str = "Move identical files of joined repositories to the root";
+
+/// Message of the commit removing placeholder files of otherwise empty directories.
+const PRUNE_COMMIT_MESSAGE: &str = "Remove placeholders of empty directories";
+
+/// Names of files only tracked to keep an otherwise empty directory in git.
+const PLACEHOLDER_FILES: &[&str] = &[".keep", ".gitkeep"];
+
+/// Number of repositories above the maximum size listed after the preflight checks.
+const MAX_REPORTED_OVERSIZED_REPOS: usize = 5;
+
+/// Number of conflicting paths listed for every branch by the preflight checks.
+const MAX_REPORTED_CONFLICTS: usize = 5;
+
+/// Directory inside `.git` of the joined repository holding the clones rewritten by filter-repo.
+const FILTER_REPO_CLONES_PATH: &str = ".git/trenza-filter-repo";
+
+/// Notes ref of the joined repository recording the source of every merged repository, see
+/// [`MergeOptions::annotate_commits`].
+pub const NOTES_REF: &str = "refs/notes/trenza";
+
+/// Subdirectory of all repositories with [`MergeOptions::flatten`].
+const FLATTEN_SUBDIR: &str = ".";
+
+/// Suffix of the temporary repository next to a bare target in which the repositories are merged,
+/// see [`MergeOptions::bare_target`].
+const BARE_WORK_SUFFIX: &str = ".trenza-work";
+
+/// Directory inside `.git` of the joined repository holding the worktree in which repository
+/// content is moved to its subdirectory before it is merged.
+const MOVE_WORKTREE_PATH: &str = ".git/trenza-move";
+
+/// How to handle conflicts when merging a repository into the joined repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnConflict {
+    /// Abort the merge and stop with an error listing the conflicting paths.
+    #[default]
+    Abort,
+    /// Resolve conflicts by taking the version of the repository being merged.
+    Theirs,
+    /// Abort the merge of the conflicting repository and continue with the next one.
+    Skip,
+}
+
+impl FromStr for OnConflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(Self::Abort),
+            "theirs" => Ok(Self::Theirs),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!(
+                "invalid conflict mode {s}, expected one of abort, theirs, skip"
+            )),
+        }
+    }
+}
+
+/// How to handle branches which would be placed in a subdirectory already used by another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnCollision {
+    /// Stop before merging and list the colliding repositories.
+    #[default]
+    Error,
+    /// Append a counter to the subdirectory, e.g. `foo-2`.
+    Suffix,
+    /// Leave out the branch which comes later in merge order.
+    Skip,
+}
+
+impl FromStr for OnCollision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "suffix" => Ok(Self::Suffix),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!(
+                "invalid collision mode {s}, expected one of error, suffix, skip"
+            )),
+        }
+    }
+}
+
+/// Default glob pattern relative to the root to discover repositories.
+pub const DEFAULT_DISCOVERY_PATTERN: &str = "**/.git";
+
+/// How the content of a repository ends up in its subdirectory of the joined repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeStrategy {
+    /// Move the content of the repository to the subdirectory in a commit on top of its branch,
+    /// then merge that commit.
+    #[default]
+    Move,
+    /// Add the repository directly in its subdirectory with `git subtree add`.
+    Subtree,
+    /// Rewrite the history of a clone of the repository with `git filter-repo` so that all of it
+    /// happened in the subdirectory, then merge the rewritten history.
+    FilterRepo,
+}
+
+impl FromStr for MergeStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "move" => Ok(Self::Move),
+            "subtree" => Ok(Self::Subtree),
+            "filter-repo" => Ok(Self::FilterRepo),
+            _ => Err(format!(
+                "invalid merge strategy {s}, expected one of move, subtree, filter-repo"
+            )),
+        }
+    }
+}
+
+/// How submodules of merged repositories are handled.
+///
+/// git only reads `.gitmodules` at the root of a repository, so submodules of merged repositories
+/// do not resolve in their subdirectory of the joined repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubmoduleMode {
+    /// Leave `.gitmodules` and the gitlinks in place and warn about them.
+    #[default]
+    Keep,
+    /// Remove `.gitmodules` and the gitlinks.
+    Ignore,
+    /// Replace the gitlinks with the content of the submodule commits they point to.
+    ///
+    /// The content is taken from the submodule checkouts of the source repository, without the
+    /// history of the submodules.
+    Inline,
+}
+
+impl FromStr for SubmoduleMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "ignore" => Ok(Self::Ignore),
+            "inline" => Ok(Self::Inline),
+            _ => Err(format!(
+                "invalid submodule mode {s}, expected one of keep, ignore, inline"
+            )),
+        }
+    }
+}
+
+/// How git LFS content of merged repositories is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LfsMode {
+    /// Merge the LFS pointer files without the content they point to and warn about them.
+    #[default]
+    Keep,
+    /// Fetch the LFS objects of every repository, copy them to the joined repository and check
+    /// them out there. Requires `git lfs`.
+    Fetch,
+}
+
+impl FromStr for LfsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "fetch" => Ok(Self::Fetch),
+            _ => Err(format!("invalid LFS mode {s}, expected one of keep, fetch")),
+        }
+    }
+}
+
+/// Line endings the text files of every repository are converted to when its content is moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EolMode {
+    /// Keep the line endings as committed in the repository.
+    #[default]
+    None,
+    /// Convert CRLF to LF.
+    Lf,
+    /// Convert LF to CRLF, except for files with `text` or `eol` attributes which git normalizes
+    /// to LF.
+    Crlf,
+}
+
+impl FromStr for EolMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "lf" => Ok(Self::Lf),
+            "crlf" => Ok(Self::Crlf),
+            _ => Err(format!(
+                "invalid line ending {s}, expected one of lf, crlf, none"
+            )),
+        }
+    }
+}
+
+/// Order in which discovered repositories are merged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RepoOrder {
+    /// Sort repository paths component by component, e.g. `repo10` before `repo2`.
+    #[default]
+    Lexical,
+    /// Sort like [`RepoOrder::Lexical`], but compare numbers by value, e.g. `repo2` before `repo10`.
+    Natural,
+    /// Keep the order in which the discovery pattern matched the repositories.
+    Discovery,
+}
+
+impl FromStr for RepoOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lexical" => Ok(Self::Lexical),
+            "natural" => Ok(Self::Natural),
+            "discovery" => Ok(Self::Discovery),
+            _ => Err(format!(
+                "invalid sort order {s}, expected one of lexical, natural, discovery"
+            )),
+        }
+    }
+}
+
+/// Options controlling which repositories below a root are discovered.
+#[derive(Debug, Clone)]
+pub struct DiscoveryOptions {
+    /// Glob pattern relative to the root matching `.git` directories or bare repositories.
+    pub pattern: String,
+    /// Maximum depth relative to the root at which repositories are considered.
+    ///
+    /// A depth of 1 only matches immediate children of the root.
+    pub max_depth: Option<usize>,
+    /// Also consider repositories nested inside other discovered repositories.
+    pub allow_nested: bool,
+    /// Glob patterns of repository paths relative to the root to consider, all if empty.
+    pub include: Vec<String>,
+    /// Glob patterns of repository paths relative to the root which are left out.
+    ///
+    /// Takes precedence over `include`.
+    pub exclude: Vec<String>,
+    /// Regular expressions matched against repository paths relative to the root, repositories
+    /// with a match anywhere in their path are left out.
+    ///
+    /// Takes precedence over `include`.
+    pub exclude_regex: Vec<String>,
+    /// File with `.gitignore` syntax listing repositories which are left out, see
+    /// [`IgnoreRules`].
+    ///
+    /// Defaults to [`IGNORE_FILE_NAME`] in the root if it exists. Takes precedence over
+    /// `include`.
+    pub ignore_file: Option<PathBuf>,
+    /// Order of the discovered repositories, which is the merge order.
+    pub sort: RepoOrder,
+    /// Also discover repositories reached through symbolic links to directories.
+    ///
+    /// Directories are only visited once, so symbolic link cycles are not followed endlessly.
+    pub follow_symlinks: bool,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        Self {
+            pattern: DEFAULT_DISCOVERY_PATTERN.to_owned(),
+            max_depth: None,
+            allow_nested: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            exclude_regex: Vec::new(),
+            ignore_file: None,
+            sort: RepoOrder::default(),
+            follow_symlinks: false,
+        }
+    }
+}
+
+/// Options controlling how repositories are merged.
+#[derive(Debug, Clone)]
+pub struct MergeOptions {
+    /// Runner for all git commands.
+    pub git: GitRunner,
+    /// Options for discovering the repositories to merge.
+    pub discovery: DiscoveryOptions,
+    /// Suffix appended to the merge root to derive the path of the joined repository.
+    pub joined_suffix: String,
+    /// Explicit path of the joined repository, takes precedence over `joined_suffix`.
+    pub target: Option<String>,
+    /// Common subdirectory of the joined repository below which all repositories are placed.
+    pub target_prefix: Option<String>,
+    /// Number of leading path components dropped from repository paths to derive their
+    /// subdirectory, e.g. `team-a/service-x` is placed in `service-x` with 1.
+    ///
+    /// The last component is always kept, entries of `path_map` are used as they are.
+    pub strip_prefix: usize,
+    /// How to handle branches which would be placed in the same subdirectory.
+    pub on_collision: OnCollision,
+    /// Subdirectories for individual repositories, keyed by their path relative to the root.
+    ///
+    /// Repositories without entry are placed in their path relative to the root.
+    pub path_map: HashMap<String, String>,
+    /// Branch to use for every repository instead of the one pointed to by the manifest.
+    pub branch: Option<String>,
+    /// Branches tried in order for repositories which do not have `branch`.
+    ///
+    /// The first existing one is merged instead, entries of `branch_map` have no fallbacks.
+    pub branch_fallbacks: Vec<String>,
+    /// Regex finding the manifest branch/tag in `git branch -r`, captured by its only group.
+    pub manifest_pattern: String,
+    /// Use the default branch of `origin` for repositories in which no manifest branch is found.
+    pub fallback_default_branch: bool,
+    /// Branches for individual repositories, keyed by their path relative to the root.
+    ///
+    /// Takes precedence over `branch` and the manifest branch.
+    pub branch_map: HashMap<String, String>,
+    /// Further branches of repositories merged into their own subdirectories, right after the
+    /// branch of their repository.
+    ///
+    /// Their history is shared with the branch of the repository, so they cannot be merged with
+    /// [`MergeStrategy::Move`].
+    pub extra_branches: Vec<ExtraBranch>,
+    /// Number of source repositories to fetch concurrently.
+    pub jobs: usize,
+    /// Number of times a failed fetch is retried.
+    pub fetch_retries: u32,
+    /// Delay before the first retry of a failed fetch, doubled for every further retry.
+    pub retry_delay: Duration,
+    /// Only fetch this many commits of history from every repository.
+    ///
+    /// Shallow fetches are never run concurrently, regardless of `jobs`.
+    pub fetch_depth: Option<u32>,
+    /// Name of the remote added for every repository, `{repo}` is replaced by the repository.
+    ///
+    /// Names are sanitized to valid remote names, the repository path is still used for its
+    /// subdirectory.
+    pub remote_name_template: String,
+    /// Fetch the tags of every repository prefixed with its name, e.g. `foo/v1.2.0`.
+    pub preserve_tags: bool,
+    /// Refspec fetched from every repository instead of the default refspec of its remote.
+    ///
+    /// `{branch}` is replaced by the branch to merge and `{remote}` by the remote name. The branch
+    /// has to end up in `refs/remotes/{remote}/{branch}`, e.g. with
+    /// `+refs/heads/{branch}:refs/remotes/{remote}/{branch}`.
+    pub fetch_refspec: Option<String>,
+    /// Message of the commit moving repository content, `{repo}` is replaced by the repository.
+    pub move_message_template: String,
+    /// Message of the commit merging the moved content of a repository instead of
+    /// `Merge <remote>/<branch> into <subdir>`.
+    ///
+    /// `{repo}` is replaced by the repository and `{branch}` by the merged branch. Only applies to
+    /// [`MergeStrategy::Move`], the other strategies create a single commit per repository named
+    /// after the move message template.
+    pub merge_message_template: Option<String>,
+    /// Name of the top-level directory where repository content is moved temporarily.
+    ///
+    /// A counter is appended if a path with that name exists in the repository content.
+    pub staging_name: String,
+    /// Move the content of a repository to its subdirectory with a single commit instead of
+    /// committing the move to the temporary path and amending it.
+    ///
+    /// The content is moved directly to the subdirectory unless a top-level entry is in the way
+    /// of it, the move commit stays separate from the merge commit in any case. Only applies to
+    /// [`MergeStrategy::Move`].
+    pub no_amend: bool,
+    /// Line endings of the text files of every repository, converted as part of its move commit.
+    ///
+    /// Binary files and files with the `-text` attribute are left alone. Only applies to
+    /// [`MergeStrategy::Move`].
+    pub normalize_eol: EolMode,
+    /// Globs of top-level entries of every repository which stay at the root of the joined
+    /// repository instead of being moved to its subdirectory.
+    ///
+    /// Entries already at the root of the joined repository, e.g. kept from a previous
+    /// repository, are moved to the subdirectory with a warning. Only applies to
+    /// [`MergeStrategy::Move`].
+    pub keep_at_root: Vec<String>,
+    /// How to handle merge conflicts.
+    pub on_conflict: OnConflict,
+    /// How repository content is placed in its subdirectory.
+    pub strategy: MergeStrategy,
+    /// Add the tree of every repository in a single commit instead of merging its history.
+    ///
+    /// The merged commit of the repository is recorded in the commit message. Takes the place of
+    /// the strategy, so it requires the default [`MergeStrategy::Move`].
+    pub squash_history: bool,
+    /// Merge the content of all repositories into the root of the joined repository instead of
+    /// their subdirectories.
+    ///
+    /// Paths of several repositories overlap and are resolved by [`MergeOptions::on_conflict`].
+    /// Requires the default [`MergeStrategy::Move`], whose move is skipped.
+    pub flatten: bool,
+    /// Keep the remotes added to the joined repository and temporary branches in the sources.
+    pub keep_remotes: bool,
+    /// Add remotes with the path of the repository relative to the joined repository, so that
+    /// kept remotes still work when the joined repository and the sources are moved together.
+    pub relative_remotes: bool,
+    /// Replace an existing target repository if it looks like it was created by trenza.
+    pub force: bool,
+    /// Stash uncommitted changes in source repositories before checking out the branch to merge.
+    ///
+    /// The changes are restored on the originally checked out branch after the merge.
+    pub stash_dirty: bool,
+    /// Fail on source repositories without commits instead of skipping them with a warning.
+    pub fail_on_empty: bool,
+    /// Size in bytes of the objects of a source repository above which it is reported by the
+    /// preflight checks.
+    pub max_repo_size: Option<u64>,
+    /// Fail the preflight checks for repositories above `max_repo_size` instead of warning.
+    pub strict: bool,
+    /// Check in the preflight checks that no two branches, or a branch and an existing target,
+    /// contain the same path of the joined repository, e.g. with nested subdirectories.
+    pub check_conflicts: bool,
+    /// Check after every merge that the subdirectory of the repository contains exactly the tree
+    /// of the merged commit, see [`RepoReport::verified`].
+    pub verify: bool,
+    /// Add a note in [`NOTES_REF`] to the commit merging every repository, naming the repository,
+    /// its branch and the merged commit.
+    pub annotate_commits: bool,
+    /// Skip checking git, the source repositories and their branches before merging.
+    pub skip_preflight: bool,
+    /// Merge into the existing repository at `target` instead of creating a new one.
+    ///
+    /// The repository has to be clean, its existing content stays in place.
+    pub into_existing: bool,
+    /// Create the joined repository as a bare repository without a working tree.
+    ///
+    /// The repositories are merged in a temporary repository next to the target, which is cloned
+    /// to the target and removed afterwards.
+    pub bare_target: bool,
+    /// Start a new joined repository with an empty commit, so that no merge happens on an unborn
+    /// branch.
+    pub seed_commit: bool,
+    /// Name of the branch of a new joined repository, regardless of the git configuration.
+    pub initial_branch: String,
+    /// Combine the top-level `.gitignore` patterns of all merged repositories in a `.gitignore` at
+    /// the root of the joined repository after the merge.
+    pub merge_gitignore: bool,
+    /// Combine the top-level `.gitattributes` lines of all merged repositories, scoped to their
+    /// subdirectory, in a `.gitattributes` at the root of the joined repository after the merge.
+    pub rewrite_gitattributes: bool,
+    /// Remove directories without tracked content from the joined repository after the merge.
+    ///
+    /// Placeholder files like `.gitkeep` in directories without other tracked files are removed
+    /// in a commit, directories left empty in the working tree are deleted.
+    pub prune_empty_dirs: bool,
+    /// Globs of top-level files which are moved to the root of the joined repository after the
+    /// merge if they are identical in all subdirectories containing them.
+    ///
+    /// Files whose copies differ are kept in their subdirectories with a warning.
+    pub dedup_root_files: Vec<String>,
+    /// Shell command run in the joined repository after all repositories are merged.
+    ///
+    /// The merged repositories are passed in [`MERGED_REPOS_ENV`], the merge fails if the command
+    /// exits with a non-zero status.
+    pub post_merge_hook: Option<String>,
+    /// Shell command run in every source repository after its branch is checked out, before it
+    /// is fetched.
+    ///
+    /// The repository and branch are passed in [`REPO_ENV`] and [`BRANCH_ENV`]. Only changes
+    /// committed to the branch are merged. A non-zero exit status fails the repository.
+    pub pre_merge_hook: Option<String>,
+    /// How submodules of merged repositories are handled.
+    pub submodules: SubmoduleMode,
+    /// Skip repositories which fail to prepare, fetch, merge or move instead of stopping the merge.
+    ///
+    /// Partial merges of failed repositories are rolled back and they are reported as
+    /// [`RepoStatus::Failed`].
+    pub continue_on_error: bool,
+    /// How git LFS content of merged repositories is handled.
+    pub lfs: LfsMode,
+    /// Continue an interrupted merge into the existing target repository.
+    ///
+    /// Repositories completed according to the state file of the target are skipped.
+    pub resume: bool,
+    /// Time after which no further repository is merged.
+    ///
+    /// The deadline is checked while preparing, before every fetch and before every merge. The
+    /// repository being merged at the deadline is finished, and the merge stops with
+    /// [`DeadlineExceeded`] so that it can be continued with `resume` if any repository was merged.
+    pub max_duration: Option<Duration>,
+    /// Merge from the remote-tracking branches already in the target repository instead of adding
+    /// and fetching remotes, e.g. ones kept by a previous merge with `keep_remotes`.
+    ///
+    /// Requires `into_existing` or `resume`.
+    pub no_fetch: bool,
+    /// Stage the content of the repositories in their subdirectories of the joined repository
+    /// as a pending merge, which is left to commit after reviewing it.
+    ///
+    /// Options creating further commits can't be combined with it.
+    pub no_commit: bool,
+    /// Allow [`MergeOptions::no_commit`] for several repositories, which are then staged as a
+    /// single merge.
+    pub single_step: bool,
+    /// Only print the merge plan without touching any git repository.
+    pub dry_run: bool,
+    /// Called for every step of the merge of a repository.
+    pub on_progress: Option<ProgressHandler>,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        Self {
+            git: GitRunner::default(),
+            discovery: DiscoveryOptions::default(),
+            joined_suffix: "_joined".to_owned(),
+            target: None,
+            target_prefix: None,
+            strip_prefix: 0,
+            on_collision: OnCollision::default(),
+            path_map: HashMap::new(),
+            branch: None,
+            branch_fallbacks: Vec::new(),
+            manifest_pattern: MANIFEST_BRANCH_PATTERN.to_owned(),
+            fallback_default_branch: false,
+            branch_map: HashMap::new(),
+            extra_branches: Vec::new(),
+            jobs: 1,
+            fetch_retries: 0,
+            retry_delay: Duration::from_secs(1),
+            fetch_depth: None,
+            remote_name_template: DEFAULT_REMOTE_NAME_TEMPLATE.to_owned(),
+            preserve_tags: false,
+            fetch_refspec: None,
+            move_message_template: DEFAULT_MOVE_MESSAGE_TEMPLATE.to_owned(),
+            staging_name: DEFAULT_STAGING_NAME.to_owned(),
+            merge_message_template: None,
+            no_amend: false,
+            normalize_eol: EolMode::default(),
+            keep_at_root: Vec::new(),
+            on_conflict: OnConflict::default(),
+            strategy: MergeStrategy::default(),
+            squash_history: false,
+            flatten: false,
+            keep_remotes: false,
+            relative_remotes: false,
+            force: false,
+            stash_dirty: false,
+            fail_on_empty: false,
+            max_repo_size: None,
+            strict: false,
+            check_conflicts: false,
+            verify: false,
+            annotate_commits: false,
+            skip_preflight: false,
+            into_existing: false,
+            bare_target: false,
+            seed_commit: true,
+            initial_branch: DEFAULT_INITIAL_BRANCH.to_owned(),
+            merge_gitignore: false,
+            rewrite_gitattributes: false,
+            prune_empty_dirs: false,
+            dedup_root_files: Vec::new(),
+            post_merge_hook: None,
+            pre_merge_hook: None,
+            submodules: SubmoduleMode::default(),
+            continue_on_error: false,
+            lfs: LfsMode::default(),
+            resume: false,
+            max_duration: None,
+            no_fetch: false,
+            no_commit: false,
+            single_step: false,
+            dry_run: false,
+            on_progress: None,
+        }
+    }
+}
+
+/// Error of a merge stopped after [`MergeOptions::max_duration`], before all repositories were
+/// merged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadlineExceeded {
+    /// Number of repositories which were done at the deadline.
+    pub done: usize,
+    /// Number of repositories to merge.
+    pub total: usize,
+    /// Whether any repository was merged before the deadline, so that the merge can be resumed.
+    pub resumable: bool,
+}
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.resumable {
+            write!(
+                f,
+                "maximum duration exceeded after {} of {} repositories, resume to merge the rest",
+                self.done, self.total
+            )
+        } else {
+            write!(
+                f,
+                "maximum duration exceeded before any of {} repositories was merged",
+                self.total
+            )
+        }
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// Error of a git executable which does not exist, see [`check_git_installed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitNotFound {
+    /// Path of the git executable.
+    pub binary: PathBuf,
+}
+
+impl fmt::Display for GitNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.binary == Path::new("git") {
+            write!(
+                f,
+                "git executable not found on PATH; install git or pass --git-binary"
+            )
+        } else {
+            write!(
+                f,
+                "git executable {} not found; install git or pass another --git-binary",
+                self.binary.display()
+            )
+        }
+    }
+}
+
+impl std::error::Error for GitNotFound {}
+
+/// Branch of a repository merged in addition to the branch chosen for it, see
+/// [`MergeOptions::extra_branches`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraBranch {
+    /// Path of the repository relative to the root.
+    pub repo: String,
+    /// Branch to merge.
+    pub branch: String,
+    /// Subdirectory of the joined repository for the branch, below the target prefix.
+    pub subdir: String,
+}
+
+/// Repository merged by [`merge_repo_list`] or [`MergeBuilder::run_list`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepoSpec {
+    /// Path of the repository.
+    pub path: PathBuf,
+    /// Name of the repository in remote names, commit messages and the merge state, also used
+    /// as key of [`MergeOptions::extra_branches`].
+    ///
+    /// Defaults to the subdirectory, or the directory name of the repository without one.
+    pub name: Option<String>,
+    /// Branch to merge, like an entry of [`MergeOptions::branch_map`].
+    ///
+    /// Without one, `branch` or the manifest branch of the options is used.
+    pub branch: Option<String>,
+    /// Subdirectory of the joined repository below the target prefix, defaults to the name.
+    pub subdir: Option<String>,
+}
+
+impl RepoSpec {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            name: None,
+            branch: None,
+            subdir: None,
+        }
+    }
+}
+
+/// Merge all repositories below `merge_root` into a adjacent git repository with the given suffix.
+///
+/// If an explicit target is set in the options, the repositories are merged there instead.
+pub fn merge_repositories(merge_root: &str, options: &MergeOptions) -> Result<MergeReport> {
+    MergeBuilder::new()
+        .root(merge_root)
+        .options(options.clone())
+        .run()
+}
+
+/// Merge exactly the repositories of `repos`, in their order, into the repository at `target`
+/// with the default [`MergeOptions`].
+///
+/// Use [`MergeBuilder::run_list`] to merge them with other options.
+pub fn merge_repo_list(repos: Vec<RepoSpec>, target: &Path) -> Result<MergeReport> {
+    MergeBuilder::new().run_list(repos, target)
+}
+
+/// Builder to configure and run a merge programmatically.
+///
+/// ```no_run
+/// use trenza::merge::MergeBuilder;
+///
+/// let report = MergeBuilder::new()
+///     .root("/home/someone/workspace/base")
+///     .target("/tmp/mono")
+///     .branch("main")
+///     .exclude("archive/**")
+///     .jobs(4)
+///     .run()?;
+/// println!("{report}");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MergeBuilder {
+    root: Option<String>,
+    options: MergeOptions,
+}
+
+impl MergeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Root directory below which repositories are merged.
+    pub fn root(mut self, root: impl Into<String>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Replace all options, e.g. to start from options assembled elsewhere.
+    pub fn options(mut self, options: MergeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Path of the joined repository, see [`MergeOptions::target`].
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.options.target = Some(target.into());
+        self
+    }
+
+    /// Suffix of the joined repository, see [`MergeOptions::joined_suffix`].
+    pub fn suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.options.joined_suffix = suffix.into();
+        self
+    }
+
+    /// Common subdirectory of all repositories, see [`MergeOptions::target_prefix`].
+    pub fn target_prefix(mut self, target_prefix: impl Into<String>) -> Self {
+        self.options.target_prefix = Some(target_prefix.into());
+        self
+    }
+
+    /// Branch to merge from every repository, see [`MergeOptions::branch`].
+    pub fn branch(mut self, branch: impl Into<String>) -> Self {
+        self.options.branch = Some(branch.into());
+        self
+    }
+
+    /// Branch to merge from a single repository, see [`MergeOptions::branch_map`].
+    pub fn repo_branch(mut self, repo: impl Into<String>, branch: impl Into<String>) -> Self {
+        self.options.branch_map.insert(repo.into(), branch.into());
+        self
+    }
+
+    /// Merge a further branch of a repository into `subdir`, see [`MergeOptions::extra_branches`].
+    pub fn extra_branch(
+        mut self,
+        repo: impl Into<String>,
+        branch: impl Into<String>,
+        subdir: impl Into<String>,
+    ) -> Self {
+        self.options.extra_branches.push(ExtraBranch {
+            repo: repo.into(),
+            branch: branch.into(),
+            subdir: subdir.into(),
+        });
+        self
+    }
+
+    /// Only merge repositories matching the glob `pattern`, see [`DiscoveryOptions::include`].
+    pub fn include(mut self, pattern: impl Into<String>) -> Self {
+        self.options.discovery.include.push(pattern.into());
+        self
+    }
+
+    /// Leave out repositories matching the glob `pattern`, see [`DiscoveryOptions::exclude`].
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.options.discovery.exclude.push(pattern.into());
+        self
+    }
+
+    /// Number of concurrent fetches, see [`MergeOptions::jobs`].
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.options.jobs = jobs;
+        self
+    }
+
+    /// Runner for git commands, see [`MergeOptions::git`].
+    pub fn git(mut self, git: GitRunner) -> Self {
+        self.options.git = git;
+        self
+    }
+
+    /// Only resolve the merge plan, see [`MergeOptions::dry_run`].
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    /// Call `handler` for every step of the merge of a repository.
+    pub fn on_progress(mut self, handler: impl Fn(&MergeEvent) + Send + Sync + 'static) -> Self {
+        self.options.on_progress = Some(ProgressHandler::new(handler));
+        self
+    }
+
+    /// Merge exactly the repositories of `repos`, in their order, into the repository at `target`.
+    ///
+    /// Unlike [`MergeBuilder::run`], no repositories are discovered, so the root, the discovery
+    /// options, `path_map`, `strip_prefix`, `branch_map`, `target` and `joined_suffix` of the
+    /// options are not used. Everything else applies like for discovered repositories.
+    pub fn run_list(&self, repos: Vec<RepoSpec>, target: &Path) -> Result<MergeReport> {
+        let options = &self.options;
+        let target_path = target
+            .to_str()
+            .with_context(|| format!("target path {} is not valid UTF-8", target.display()))?;
+        validate_options(options)?;
+        for spec in repos.iter() {
+            if let Some(subdir) = &spec.subdir {
+                validate_subdir(&spec.path.display().to_string(), subdir)?;
+            }
+        }
+        info!(
+            "{} repositories will be merged to {target_path}",
+            repos.len()
+        );
+
+        // Git is checked in the current directory, as there is no root.
+        merge_specs(repos, target_path, ".", options)
+    }
+
+    /// Run the merge.
+    pub fn run(&self) -> Result<MergeReport> {
+        let merge_root = self
+            .root
+            .as_deref()
+            .with_context(|| "no root directory configured")?;
+        let options = &self.options;
+
+        let target_path = target_path(merge_root, options)?;
+        validate_options(options)?;
+        validate_path_map(&options.path_map)?;
+        info!("Repositories below {merge_root} will be merge to {target_path}");
+
+        let mut repos = discover_repos(merge_root, &options.discovery)
+            .with_context(|| "failed to find repositories")?;
+        if options.into_existing && !Path::new(&target_path).exists() {
+            bail!("target repository {target_path} does not exist");
+        }
+        // An existing target matching the pattern, e.g. from a previous run or reached through a
+        // symbolic link, must not be merged into itself.
+        if let Ok(target) = fs::canonicalize(&target_path) {
+            repos.retain(|repo| {
+                let is_target = fs::canonicalize(repo).is_ok_and(|repo| repo == target);
+                if is_target {
+                    debug!("Leaving out the target repository {}", repo.display());
+                }
+                !is_target
+            });
+        }
+        info!("Found {} repositories to merge", repos.len());
+        for extra in options.extra_branches.iter() {
+            let found = repos.iter().any(|repo| {
+                relative_repo_name(repo, merge_root).is_ok_and(|name| name == extra.repo)
+            });
+            if !found {
+                warn!(
+                    "Repo {} of extra branch {} was not found, the branch is not merged",
+                    extra.repo, extra.branch
+                );
+            }
+        }
+
+        let specs = discovered_specs(&repos, merge_root, options)?;
+        merge_specs(specs, &target_path, merge_root, options)
+    }
+}
+
+/// Check the options of a merge which do not depend on the repositories.
+fn validate_options(options: &MergeOptions) -> Result<()> {
+    validate_move_message_template(&options.move_message_template)?;
+    validate_staging_name(&options.staging_name)?;
+    if let Some(template) = &options.merge_message_template {
+        validate_merge_message_template(template)?;
+    }
+    validate_remote_name_template(&options.remote_name_template)?;
+    manifest_regex(&options.manifest_pattern)?;
+    validate_extra_branches(options)?;
+    if options.no_fetch && !options.into_existing && !options.resume {
+        bail!("merging without fetching requires an existing target repository with remotes");
+    }
+    if options.bare_target
+        && (options.into_existing || options.resume || options.keep_remotes || options.no_commit)
+    {
+        bail!("a bare target can only be created from scratch, without kept remotes or staging");
+    }
+    if options.flatten {
+        if options.strategy != MergeStrategy::Move || options.squash_history || options.no_commit {
+            bail!("flattening can only be combined with the default strategy");
+        }
+        if options.target_prefix.is_some()
+            || !options.path_map.is_empty()
+            || !options.extra_branches.is_empty()
+        {
+            bail!("flattening cannot be combined with subdirectories of repositories");
+        }
+        if !options.keep_at_root.is_empty()
+            || options.normalize_eol != EolMode::None
+            || options.verify
+            || options.merge_gitignore
+            || options.rewrite_gitattributes
+            || !options.dedup_root_files.is_empty()
+            || options.submodules != SubmoduleMode::Keep
+        {
+            bail!("flattening cannot be combined with options handling subdirectories");
+        }
+    }
+    if options.relative_remotes && !options.keep_remotes {
+        warn!("Relative remotes are removed after the merge unless they are kept");
+    }
+    if options.normalize_eol != EolMode::None && options.strategy != MergeStrategy::Move {
+        bail!("normalizing line endings is only supported by the move strategy");
+    }
+    if options.squash_history {
+        if options.strategy != MergeStrategy::Move {
+            bail!("squashing history cannot be combined with the subtree or filter-repo strategy");
+        }
+        if options.normalize_eol != EolMode::None {
+            bail!("normalizing line endings cannot be combined with squashing history");
+        }
+    }
+    if options.verify && options.normalize_eol != EolMode::None {
+        bail!("merges with normalized line endings cannot be verified");
+    }
+    root_patterns(&options.keep_at_root, "keep at root")?;
+    root_patterns(&options.dedup_root_files, "dedup root files")?;
+    if !options.keep_at_root.is_empty() {
+        if options.strategy != MergeStrategy::Move || options.squash_history || options.no_commit {
+            bail!("keeping files at the root is only supported by the move strategy");
+        }
+        if options.verify {
+            bail!("merges keeping files at the root cannot be verified");
+        }
+    }
+    if options.no_commit {
+        if options.strategy != MergeStrategy::Move || options.squash_history {
+            bail!("staging without committing replaces the strategy and squashing history");
+        }
+        if options.verify
+            || options.annotate_commits
+            || options.normalize_eol != EolMode::None
+            || options.merge_gitignore
+            || options.rewrite_gitattributes
+            || options.prune_empty_dirs
+            || !options.dedup_root_files.is_empty()
+            || options.submodules != SubmoduleMode::Keep
+        {
+            bail!("staging without committing cannot be combined with options creating commits");
+        }
+    }
+
+    Ok(())
+}
+
+/// Merge the repositories of `specs` into the joined repository at `target_path`, running git
+/// checks in `dir`.
+fn merge_specs(
+    specs: Vec<RepoSpec>,
+    target_path: &str,
+    dir: &str,
+    options: &MergeOptions,
+) -> Result<MergeReport> {
+    if options.strategy == MergeStrategy::FilterRepo && !options.dry_run && !options.no_fetch {
+        check_filter_repo(&options.git, dir)?;
+    }
+    if options.lfs == LfsMode::Fetch && !options.dry_run {
+        check_git_lfs(&options.git, dir)?;
+    }
+
+    let entries = merge_entries(specs, options);
+    if options.on_collision == OnCollision::Error {
+        validate_no_collisions(&entries)?;
+    }
+    if options.no_commit && entries.len() > 1 && !options.single_step {
+        bail!(
+            "staging without committing leaves a single merge to review, pass --single-step to \
+             stage all {} repositories in it",
+            entries.len()
+        );
+    }
+
+    if !options.skip_preflight {
+        // A resumed target has content of the repositories merged before.
+        let existing_target = (options.into_existing && !options.resume).then_some(target_path);
+        preflight(&entries, dir, existing_target, options)
+            .with_context(|| "preflight checks failed")?;
+    }
+
+    if options.bare_target && !options.dry_run {
+        return merge_into_bare(entries, target_path, options);
+    }
+
+    if !options.dry_run && !options.resume {
+        if options.into_existing {
+            check_existing_repo(target_path, options)
+                .with_context(|| "failed to use existing target repository")?;
+        } else {
+            create_joined_repo(target_path, options)
+                .with_context(|| "failed to create target repository")?;
+            if options.seed_commit {
+                ensure_initial_commit(&options.git, target_path)?;
+            }
+        }
+    }
+
+    let (repos, remotes) = merge_repos(entries, target_path, options)
+        .with_context(|| "failed to merge repositories")?;
+
+    Ok(MergeReport {
+        target: PathBuf::from(target_path),
+        repos,
+        remotes,
+    })
+}
+
+/// Merge `entries` in a temporary repository next to `target_path` and clone it to a bare
+/// repository at `target_path`, see [`MergeOptions::bare_target`].
+///
+/// The temporary repository is removed whether the merge succeeds or not.
+fn merge_into_bare(
+    entries: Vec<MergeEntry>,
+    target_path: &str,
+    options: &MergeOptions,
+) -> Result<MergeReport> {
+    let git = &options.git;
+    // Fail before merging if the target is in the way.
+    let replace_target = check_replaceable_target(target_path, options)?;
+
+    let work_path = format!("{}{BARE_WORK_SUFFIX}", target_path.trim_end_matches('/'));
+    if Path::new(&work_path).exists() {
+        // Temporary repositories are left behind by interrupted runs.
+        if !looks_like_joined_repo(git, &work_path, &options.move_message_template) {
+            bail!("{work_path} is in the way of the temporary repository; refusing to remove it");
+        }
+        warn!("Removing temporary repository {work_path} of a previous run");
+        fs::remove_dir_all(&work_path)
+            .with_context(|| format!("failed to remove temporary repository {work_path}"))?;
+    }
+
+    let res = (|| -> Result<_> {
+        create_joined_repo(&work_path, options)
+            .with_context(|| "failed to create temporary repository")?;
+        if options.seed_commit {
+            ensure_initial_commit(git, &work_path)?;
+        }
+        // Remotes are never kept, the target has no remotes.
+        let (repos, _) = merge_repos(entries, &work_path, options)
+            .with_context(|| "failed to merge repositories")?;
+
+        if replace_target {
+            warn!("Removing existing target repository {target_path}");
+            fs::remove_dir_all(target_path).with_context(|| {
+                format!("failed to remove existing target repository {target_path}")
+            })?;
+        }
+        // A mirror keeps all refs like notes, the temporary repository is removed as origin.
+        git.command(&work_path)
+            .args([
+                "clone",
+                "--quiet",
+                "--mirror",
+                "--",
+                &work_path,
+                target_path,
+            ])
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("cloning the joined repository to {target_path} failed"))?;
+        git.command(target_path)
+            .args(["remote", "remove", "origin"])
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("removing origin of {target_path} failed"))?;
+
+        Ok(repos)
+    })();
+
+    if let Err(err) = fs::remove_dir_all(&work_path) {
+        warn!("Failed to remove temporary repository {work_path}: {err}");
+    }
+
+    Ok(MergeReport {
+        target: PathBuf::from(target_path),
+        repos: res?,
+        remotes: BTreeMap::new(),
+    })
+}
+
+/// List all repositories below `root` in the order in which they would be merged with the default
+/// [`DiscoveryOptions`], see [`find_repos`] and [`discover_repositories`].
+pub fn list_repos(root: &str) -> Result<Vec<PathBuf>> {
+    find_repos(root)
+}
+
+/// List all repositories below `root` matching `options` in the order in which they would be
+/// merged.
+pub fn discover_repositories(root: &str, options: &DiscoveryOptions) -> Result<Vec<PathBuf>> {
+    discover_repos(root, options).with_context(|| "failed to find repositories")
+}
+
+/// Find all git repositories below `root` with the default [`DiscoveryOptions`].
+///
+/// Repositories are found by matching [`DEFAULT_DISCOVERY_PATTERN`] below `root`, each `.git`
+/// match stands for its parent directory. Repositories nested inside other repositories are left
+/// out. The paths start with `root` and are sorted lexically by their components, so the result
+/// is the same for the same directory tree regardless of the file system.
+pub fn find_repos(root: &str) -> Result<Vec<PathBuf>> {
+    discover_repositories(root, &DiscoveryOptions::default())
+}
+
+/// Remove the joined repository at `target_path` created by a previous merge.
+///
+/// Unless `force` is set in the options, the repository has to contain move commits matching
+/// the move message template of the options.
+pub fn undo_merge(target_path: &str, options: &MergeOptions) -> Result<()> {
+    let path = Path::new(target_path);
+    if !path.is_dir() {
+        bail!("{target_path} is not a directory");
+    }
+
+    if !options.force {
+        if !is_repo_dir(path) {
+            bail!("{target_path} is not a git repository; refusing to remove it");
+        }
+
+        if !looks_like_joined_repo(&options.git, target_path, &options.move_message_template) {
+            bail!(
+                "{target_path} has no commits matching the move message template {:?}, \
+                 so it does not look like it was created by trenza; refusing to remove it",
+                options.move_message_template
+            );
+        }
+    }
+
+    info!("Removing joined repository {target_path}");
+    fs::remove_dir_all(target_path)
+        .with_context(|| format!("failed to remove joined repository {target_path}"))
+}
+
+/// Split `subdir` of the joined repository at `target_path` out into a new repository at
+/// `output_path`, with the history of the subdirectory as computed by `git subtree split`.
+///
+/// The branch of the new repository is named after [`MergeOptions::initial_branch`].
+pub fn split_subdir(
+    target_path: &str,
+    subdir: &str,
+    output_path: &str,
+    options: &MergeOptions,
+) -> Result<()> {
+    let git = &options.git;
+    let subdir = subdir.trim_matches('/');
+    if Path::new(output_path).exists() {
+        bail!("{output_path} exists already");
+    }
+    // The clone runs in the joined repository.
+    let output = std::env::current_dir()
+        .with_context(|| "failed to get the current directory")?
+        .join(output_path);
+    git.command(target_path)
+        .args([
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("HEAD:{subdir}"),
+        ])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("{subdir} is not a directory of {target_path}"))?;
+
+    // A branch left behind by an earlier split would make `git subtree` build on it.
+    let _ = git
+        .command(target_path)
+        .args(["branch", "-D", TMP_SPLIT_BRANCH])
+        .output();
+    info!("Splitting {subdir} out of {target_path}");
+    let res = git
+        .command(target_path)
+        .args([
+            "subtree",
+            "split",
+            &format!("--prefix={subdir}"),
+            "--branch",
+            TMP_SPLIT_BRANCH,
+            "HEAD",
+        ])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("git subtree split of {subdir} failed"))
+        .and_then(|_| {
+            // Without `--no-local`, all objects of the joined repository would be copied.
+            git.command(target_path)
+                .args([
+                    "clone",
+                    "--quiet",
+                    "--no-local",
+                    "--single-branch",
+                    "--branch",
+                    TMP_SPLIT_BRANCH,
+                    ".",
+                ])
+                .arg(&output)
+                .output()
+                .to_anyhow()
+                .with_context(|| format!("clone of the split history to {output_path} failed"))
+        });
+    let _ = git
+        .command(target_path)
+        .args(["branch", "-D", TMP_SPLIT_BRANCH])
+        .output();
+    res?;
+
+    git.command(output_path)
+        .args(["branch", "-m", &options.initial_branch])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("renaming the branch of {output_path} failed"))?;
+    git.command(output_path)
+        .args(["remote", "remove", "origin"])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("removing the remote of {output_path} failed"))?;
+
+    Ok(())
+}
+
+/// Run the preflight checks of a merge on the repositories below `root`, without touching any
+/// repository.
+///
+/// Besides the checks of every repository, repositories configured in the branch and path maps
+/// or with extra branches are reported as problems if they are not found below the root.
+pub fn check_repositories(root: &str, options: &MergeOptions) -> Result<Vec<RepoCheck>> {
+    manifest_regex(&options.manifest_pattern)?;
+    validate_path_map(&options.path_map)?;
+    validate_extra_branches(options)?;
+    check_git(&options.git, root)?;
+
+    let repos = discover_repositories(root, &options.discovery)?;
+    let entries = merge_entries(discovered_specs(&repos, root, options)?, options);
+    let mut checks = check_repos(&entries, None, options)?;
+
+    let found: HashSet<_> = checks.iter().map(|check| check.repo.clone()).collect();
+    let mut missing: Vec<_> = options
+        .branch_map
+        .keys()
+        .chain(options.path_map.keys())
+        .chain(options.extra_branches.iter().map(|extra| &extra.repo))
+        .filter(|repo| !found.contains(*repo))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    missing.sort();
+    for repo in missing {
+        checks.push(RepoCheck {
+            repo: repo.clone(),
+            branch: options.branch_map.get(repo).cloned(),
+            subdir: target_subdir(repo, options),
+            empty: false,
+            problems: vec!["configured repository not found below the root".to_owned()],
+        });
+    }
+
+    Ok(checks)
+}
+
+/// Merge settings loaded from a TOML file, e.g.
+///
+/// ```toml
+/// root = "workspace"
+/// suffix = "_mono"
+/// branch = "main"
+/// exclude = ["archive/**"]
+///
+/// [repos."vendor/libfoo"]
+/// branch = "release"
+/// path = "external/foo"
+///
+/// [repos."tools/cli"]
+/// extra_branches = [{ branch = "release-1.x", path = "tools/cli-1.x" }]
+/// ```
+///
+/// Repository paths in `repos` are relative to the root. Every setting is optional, command line
+/// flags take precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Root directory below which to merge repositories.
+    pub root: Option<String>,
+    /// Path of the joined repository.
+    pub target: Option<String>,
+    /// Suffix appended to the root for the joined repository if there is no target.
+    pub suffix: Option<String>,
+    /// Branch to merge from every repository.
+    pub branch: Option<String>,
+    /// Glob patterns of repository paths relative to the root to consider.
+    pub include: Vec<String>,
+    /// Glob patterns of repository paths relative to the root which are left out.
+    pub exclude: Vec<String>,
+    /// Settings of individual repositories, keyed by their path relative to the root.
+    pub repos: HashMap<String, RepoConfig>,
+}
+
+/// Settings of a single repository in a [`Config`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoConfig {
+    /// Branch to merge, see [`MergeOptions::branch_map`].
+    pub branch: Option<String>,
+    /// Subdirectory of the joined repository, see [`MergeOptions::path_map`].
+    pub path: Option<String>,
+    /// Further branches to merge, see [`MergeOptions::extra_branches`].
+    pub extra_branches: Vec<ExtraBranchConfig>,
+}
+
+/// Further branch of a repository in a [`RepoConfig`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtraBranchConfig {
+    /// Branch to merge.
+    pub branch: String,
+    /// Subdirectory of the joined repository for the branch.
+    pub path: String,
+}
+
+impl Config {
+    /// Load the config from the TOML file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+
+        toml::from_str(&content)
+            .with_context(|| format!("failed to parse config {}", path.display()))
+    }
+
+    /// Branches of the repositories which have one configured.
+    pub fn branch_map(&self) -> HashMap<String, String> {
+        self.repos
+            .iter()
+            .filter_map(|(repo, config)| Some((repo.clone(), config.branch.clone()?)))
+            .collect()
+    }
+
+    /// Subdirectories of the repositories which have one configured.
+    pub fn path_map(&self) -> HashMap<String, String> {
+        self.repos
+            .iter()
+            .filter_map(|(repo, config)| Some((repo.clone(), config.path.clone()?)))
+            .collect()
+    }
+
+    /// Further branches of all repositories, ordered by repository.
+    pub fn extra_branches(&self) -> Vec<ExtraBranch> {
+        let mut extra_branches: Vec<_> = self
+            .repos
+            .iter()
+            .flat_map(|(repo, config)| {
+                config.extra_branches.iter().map(|extra| ExtraBranch {
+                    repo: repo.clone(),
+                    branch: extra.branch.clone(),
+                    subdir: extra.path.clone(),
+                })
+            })
+            .collect();
+        // Repositories come in hash order, branches of the same one stay in config order.
+        extra_branches.sort_by(|a, b| a.repo.cmp(&b.repo));
+
+        extra_branches
+    }
+}
+
+/// Load a file mapping repository paths relative to the root to the branch to merge.
+///
+/// Every non-empty line not starting with `#` has the form `repo = branch`. Keys and values
+/// may be quoted, so simple TOML files with string values are accepted as well.
+pub fn load_branch_map(path: &Path) -> Result<HashMap<String, String>> {
+    load_map(path, "branch map", "branch")
+}
+
+/// Load a file mapping repository paths relative to the root to their target subdirectory.
+///
+/// The format is the same as for [`load_branch_map`], with lines of the form `repo = subdir`.
+pub fn load_path_map(path: &Path) -> Result<HashMap<String, String>> {
+    let path_map = load_map(path, "path map", "subdir")?;
+    validate_path_map(&path_map).with_context(|| format!("invalid path map {}", path.display()))?;
+
+    Ok(path_map)
+}
+
+/// Load a file of `repo = value` lines as used for branch and path maps.
+fn load_map(path: &Path, kind: &str, value_name: &str) -> Result<HashMap<String, String>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {kind} {}", path.display()))?;
+
+    let mut map = HashMap::new();
+    for (idx, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((repo, value)) = line.split_once('=') else {
+            bail!(
+                "invalid entry in line {} of {kind} {}: expected `repo = {value_name}`",
+                idx + 1,
+                path.display()
+            );
+        };
+
+        let unquote = |value: &str| value.trim().trim_matches('"').to_owned();
+        map.insert(unquote(repo), unquote(value));
+    }
+
+    Ok(map)
+}
+
+/// Check that all target subdirectories stay inside the joined repository.
+fn validate_path_map(path_map: &HashMap<String, String>) -> Result<()> {
+    for (repo, subdir) in path_map {
+        validate_subdir(repo, subdir)?;
+    }
+
+    Ok(())
+}
+
+/// Check that extra branches can be merged and stay inside the joined repository.
+fn validate_extra_branches(options: &MergeOptions) -> Result<()> {
+    if options.extra_branches.is_empty() {
+        return Ok(());
+    }
+    if options.strategy == MergeStrategy::Move {
+        // Merging a branch with history shared with an already moved branch would merge its
+        // changes into the moved files instead of placing it in its own subdirectory.
+        bail!("extra branches require the subtree or filter-repo strategy");
+    }
+    for extra in options.extra_branches.iter() {
+        validate_subdir(&extra.repo, &extra.subdir)?;
+    }
+
+    Ok(())
+}
+
+fn validate_subdir(repo: &str, subdir: &str) -> Result<()> {
+    let subdir = subdir.trim_matches('/');
+    if subdir.is_empty()
+        || subdir
+            .split('/')
+            .any(|part| matches!(part, "" | "." | ".."))
+    {
+        bail!("target subdirectory {subdir:?} of repo {repo} must be a relative path inside the joined repository");
+    }
+
+    Ok(())
+}
+
+fn target_path(merge_root: &str, options: &MergeOptions) -> Result<String> {
+    let target_path = match &options.target {
+        Some(target) => target.clone(),
+        None => format!("{}{}", merge_root, options.joined_suffix),
+    };
+
+    // The root exists, so the target can only be the same directory if it exists as well.
+    if Path::new(&target_path).exists()
+        && fs::canonicalize(&target_path)? == fs::canonicalize(merge_root)?
+    {
+        bail!("target repository {target_path} is the same directory as the root {merge_root}");
+    }
+    // A new target below the root would be found as a repository to merge when rerunning, an
+    // existing one is left out of the repositories instead.
+    if !options.into_existing
+        && resolve_path(Path::new(&target_path))?.starts_with(fs::canonicalize(merge_root)?)
+    {
+        bail!(
+            "target repository {target_path} is inside the root {merge_root}, choose a target \
+             outside of it"
+        );
+    }
+
+    Ok(target_path)
+}
+
+/// Make `path` absolute and resolve symbolic links in the part of it which exists.
+fn resolve_path(path: &Path) -> Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        let (Some(parent), Some(name)) = (existing.parent(), existing.file_name()) else {
+            break;
+        };
+        missing.push(name);
+        existing = parent;
+    }
+
+    // An empty parent stands for the working directory.
+    let existing = if existing.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        existing
+    };
+    let mut resolved = fs::canonicalize(existing)
+        .with_context(|| format!("failed to resolve {}", existing.display()))?;
+    resolved.extend(missing.iter().rev());
+
+    Ok(resolved)
+}
+
+/// Check that the existing repository at `target_path` can be merged into.
+fn check_existing_repo(target_path: &str, options: &MergeOptions) -> Result<()> {
+    let path = PathBuf::from(target_path);
+    if !path.join(".git").is_dir() {
+        bail!("{target_path} is not a git repository");
+    }
+
+    if is_dirty(&options.git, &path)? {
+        bail!("{target_path} has uncommitted changes, commit or stash them first");
+    }
+
+    Ok(())
+}
+
+fn discover_repos(root: &str, options: &DiscoveryOptions) -> Result<Vec<PathBuf>> {
+    let matches = if options.follow_symlinks {
+        let pattern = Pattern::new(&options.pattern)
+            .with_context(|| format!("invalid discovery pattern {}", options.pattern))?;
+        walk_matches(Path::new(root), &pattern)
+    } else {
+        let pattern = Path::new(root).join(&options.pattern);
+        let pattern = pattern.to_str().with_context(|| {
+            format!("discovery pattern {} is not valid UTF-8", pattern.display())
+        })?;
+        glob(pattern)?.filter_map(|res| res.ok()).collect()
+    };
+
+    let mut paths: Vec<_> = matches
+        .into_iter()
+        .filter_map(|path| {
+            if path.file_name().is_some_and(|name| name == ".git") {
+                path.parent().map(|path| path.to_owned())
+            } else {
+                // Bare repositories are matched directly.
+                Some(path)
+            }
+        })
+        .filter(|path| {
+            // glob follows symbolic links as well, so repositories reached through them are
+            // dropped afterwards.
+            options.follow_symlinks || !through_symlink(path, Path::new(root))
+        })
+        .filter(|path| match options.max_depth {
+            Some(max_depth) => path
+                .strip_prefix(root)
+                .is_ok_and(|rel_path| rel_path.components().count() <= max_depth),
+            None => true,
+        })
+        .collect();
+    let discovered = paths.clone();
+
+    // Make merge order deterministic.
+    paths.sort();
+
+    if !options.allow_nested {
+        // Sorting by components places nested repositories directly after their parents.
+        let mut top_level: Vec<PathBuf> = Vec::with_capacity(paths.len());
+        for path in paths {
+            match top_level.last() {
+                Some(parent) if path.starts_with(parent) => {
+                    debug!("Skipping nested repository {}", path.display());
+                }
+                _ => top_level.push(path),
+            }
+        }
+        paths = top_level;
+    }
+
+    match options.sort {
+        RepoOrder::Lexical => (),
+        RepoOrder::Natural => paths.sort_by(|a, b| natural_path_cmp(a, b)),
+        RepoOrder::Discovery => {
+            let kept: HashSet<_> = paths.into_iter().collect();
+            paths = discovered
+                .into_iter()
+                .filter(|path| kept.contains(path))
+                .collect();
+        }
+    }
+
+    filter_repos(paths, root, options)
+}
+
+/// Find all paths below `root` matching `pattern`, following symbolic links to directories.
+///
+/// Every directory is visited once by its canonical path, which ends symbolic link cycles. Paths
+/// keep the components by which they were reached from `root`. `.git` directories are not
+/// searched.
+fn walk_matches(root: &Path, pattern: &Pattern) -> Vec<PathBuf> {
+    // Like glob, wildcards only match within a path component.
+    let match_options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    let mut matches = Vec::new();
+    let mut visited = HashSet::new();
+    let mut dirs = vec![root.to_owned()];
+
+    while let Some(dir) = dirs.pop() {
+        let Ok(canonical) = fs::canonicalize(&dir) else {
+            continue;
+        };
+        if !visited.insert(canonical) {
+            debug!("Skipping {}, it was searched before", dir.display());
+            continue;
+        }
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+
+        let mut entries: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect();
+        entries.sort();
+
+        let mut subdirs = Vec::new();
+        for path in entries {
+            if path
+                .strip_prefix(root)
+                .is_ok_and(|rel_path| pattern.matches_path_with(rel_path, match_options))
+            {
+                matches.push(path.clone());
+            }
+            if path.is_dir() && path.file_name().is_some_and(|name| name != ".git") {
+                subdirs.push(path);
+            }
+        }
+        // Search subdirectories in order.
+        dirs.extend(subdirs.into_iter().rev());
+    }
+
+    matches
+}
+
+/// Check whether `path` or one of its parents below `root` is a symbolic link.
+fn through_symlink(path: &Path, root: &Path) -> bool {
+    path.ancestors()
+        .take_while(|ancestor| *ancestor != root)
+        .any(|ancestor| {
+            fs::symlink_metadata(ancestor).is_ok_and(|metadata| metadata.file_type().is_symlink())
+        })
+}
+
+/// Compare paths component by component with [`natural_cmp`].
+fn natural_path_cmp(a: &Path, b: &Path) -> cmp::Ordering {
+    let mut a = a.components();
+    let mut b = b.components();
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return cmp::Ordering::Equal,
+            (None, Some(_)) => return cmp::Ordering::Less,
+            (Some(_), None) => return cmp::Ordering::Greater,
+            (Some(a), Some(b)) => {
+                let ordering = natural_cmp(
+                    &a.as_os_str().to_string_lossy(),
+                    &b.as_os_str().to_string_lossy(),
+                );
+                if ordering != cmp::Ordering::Equal {
+                    return ordering;
+                }
+            }
+        }
+    }
+}
+
+/// Compare strings character by character, but runs of digits by their numeric value.
+///
+/// Numbers which only differ in leading zeros are ordered by their length, e.g. `7` before `007`.
+fn natural_cmp(mut a: &str, mut b: &str) -> cmp::Ordering {
+    let is_digit = |c: char| c.is_ascii_digit();
+    loop {
+        match (a.chars().next(), b.chars().next()) {
+            (None, None) => return cmp::Ordering::Equal,
+            (None, Some(_)) => return cmp::Ordering::Less,
+            (Some(_), None) => return cmp::Ordering::Greater,
+            (Some(x), Some(y)) if is_digit(x) && is_digit(y) => {
+                let a_end = a.find(|c| !is_digit(c)).unwrap_or(a.len());
+                let b_end = b.find(|c| !is_digit(c)).unwrap_or(b.len());
+                let a_number = a[..a_end].trim_start_matches('0');
+                let b_number = b[..b_end].trim_start_matches('0');
+
+                // Without leading zeros, longer numbers are larger.
+                let ordering = a_number
+                    .len()
+                    .cmp(&b_number.len())
+                    .then_with(|| a_number.cmp(b_number))
+                    .then_with(|| a_end.cmp(&b_end));
+                if ordering != cmp::Ordering::Equal {
+                    return ordering;
+                }
+                a = &a[a_end..];
+                b = &b[b_end..];
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a = &a[x.len_utf8()..];
+                b = &b[y.len_utf8()..];
+            }
+        }
+    }
+}
+
+/// Keep repositories whose path relative to `root` matches any include pattern (or all if there
+/// are none) and neither an exclude pattern nor an exclude regex.
+fn filter_repos(
+    mut repos: Vec<PathBuf>,
+    root: &str,
+    options: &DiscoveryOptions,
+) -> Result<Vec<PathBuf>> {
+    let compile = |patterns: &[String], kind: &str| {
+        patterns
+            .iter()
+            .map(|pattern| {
+                Pattern::new(pattern).with_context(|| format!("invalid {kind} pattern {pattern}"))
+            })
+            .collect::<Result<Vec<_>>>()
+    };
+    let includes = compile(&options.include, "include")?;
+    let excludes = compile(&options.exclude, "exclude")?;
+    let exclude_regexes = options
+        .exclude_regex
+        .iter()
+        .map(|regex| Regex::new(regex).with_context(|| format!("invalid exclude regex {regex}")))
+        .collect::<Result<Vec<_>>>()?;
+    let ignore_rules = match &options.ignore_file {
+        Some(path) => IgnoreRules::load(path)?,
+        None => {
+            let path = Path::new(root).join(IGNORE_FILE_NAME);
+            if path.is_file() {
+                IgnoreRules::load(&path)?
+            } else {
+                IgnoreRules::default()
+            }
+        }
+    };
+
+    repos.retain(|path| {
+        let rel_path =
+            relative_repo_name(path, root).unwrap_or_else(|_| path.to_string_lossy().into_owned());
+        let included =
+            includes.is_empty() || includes.iter().any(|pattern| pattern.matches(&rel_path));
+        let excluded = excludes.iter().any(|pattern| pattern.matches(&rel_path))
+            || exclude_regexes
+                .iter()
+                .any(|regex| regex.is_match(&rel_path))
+            || ignore_rules.is_ignored(&rel_path);
+
+        if included && excluded {
+            debug!("Excluding repository {rel_path}");
+        }
+        included && !excluded
+    });
+
+    Ok(repos)
+}
+
+fn validate_move_message_template(template: &str) -> Result<()> {
+    if template.trim().is_empty() {
+        bail!("move message template must not be empty");
+    }
+
+    if template.matches("{repo}").count() > 1 {
+        bail!("move message template {template:?} may contain {{repo}} at most once");
+    }
+
+    Ok(())
+}
+
+fn validate_merge_message_template(template: &str) -> Result<()> {
+    if template.trim().is_empty() {
+        bail!("merge message template must not be empty");
+    }
+
+    let placeholder_re = Regex::new(r"\{[^{}]*\}").expect("valid placeholder regex");
+    for placeholder in placeholder_re.find_iter(template) {
+        if !matches!(placeholder.as_str(), "{repo}" | "{branch}") {
+            bail!(
+                "merge message template {template:?} contains unknown placeholder {}, expected \
+                 {{repo}} or {{branch}}",
+                placeholder.as_str()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the staging directory is a single path component next to the repository content.
+fn validate_staging_name(name: &str) -> Result<()> {
+    if name.is_empty() || matches!(name, "." | ".." | ".git") || name.contains(['/', '\\']) {
+        bail!("invalid staging directory name {name:?}, it must be a plain directory name");
+    }
+
+    Ok(())
+}
+
+/// Compile the regex finding the manifest branch, which has to capture the branch in one group.
+fn manifest_regex(pattern: &str) -> Result<Regex> {
+    let re =
+        Regex::new(pattern).with_context(|| format!("invalid manifest pattern {pattern:?}"))?;
+
+    // The implicit group of the whole match is counted as well.
+    if re.captures_len() != 2 {
+        bail!(
+            "manifest pattern {pattern:?} must contain exactly one capture group for the branch, \
+             found {}",
+            re.captures_len() - 1
+        );
+    }
+
+    Ok(re)
+}
+
+fn validate_remote_name_template(template: &str) -> Result<()> {
+    if template.matches("{repo}").count() != 1 {
+        bail!("remote name template {template:?} must contain {{repo}} exactly once");
+    }
+
+    Ok(())
+}
+
+fn check_filter_repo(git: &GitRunner, dir: &str) -> Result<()> {
+    let available = git
+        .command(dir)
+        .args(["filter-repo", "--version"])
+        .output()
+        .is_ok_and(|output| output.status.success());
+
+    if !available {
+        bail!(
+            "the filter-repo strategy requires git-filter-repo, install it with your package \
+             manager or `pip install git-filter-repo`, see https://github.com/newren/git-filter-repo"
+        );
+    }
+
+    Ok(())
+}
+
+fn check_git_lfs(git: &GitRunner, dir: &str) -> Result<()> {
+    let available = git
+        .command(dir)
+        .args(["lfs", "version"])
+        .output()
+        .is_ok_and(|output| output.status.success());
+
+    if !available {
+        bail!(
+            "--lfs fetch requires git lfs, install it with your package manager, \
+             see https://git-lfs.com"
+        );
+    }
+
+    Ok(())
+}
+
+/// Check whether there is a target repository at `target_path` which may be replaced.
+///
+/// Existing targets are only replaced with `force` if they look like they were created by trenza.
+fn check_replaceable_target(target_path: &str, options: &MergeOptions) -> Result<bool> {
+    if !Path::new(target_path).exists() {
+        return Ok(false);
+    }
+    if !options.force {
+        bail!("target repository {target_path} already exists; refusing to overwrite");
+    }
+
+    if !looks_like_joined_repo(&options.git, target_path, &options.move_message_template) {
+        bail!(
+            "target repository {target_path} does not look like it was created by trenza; \
+             refusing to remove it"
+        );
+    }
+
+    Ok(true)
+}
+
+fn create_joined_repo(target_path: &str, options: &MergeOptions) -> Result<()> {
+    let git = &options.git;
+    if check_replaceable_target(target_path, options)? {
+        warn!("Removing existing target repository {target_path}");
+        fs::remove_dir_all(target_path).with_context(|| {
+            format!("failed to remove existing target repository {target_path}")
+        })?;
+    }
+
+    fs::create_dir(target_path)?;
+
+    let branch = &options.initial_branch;
+    let init = git
+        .command(target_path)
+        .args(["init", "-b", branch])
+        .output()
+        .to_anyhow();
+    if let Err(err) = init {
+        // Git before 2.28 has no `-b`, the unborn branch is renamed with the HEAD reference then.
+        debug!("git init -b failed, setting the initial branch afterwards: {err:#}");
+        git.command(target_path)
+            .args(["init"])
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("git init in {target_path} failed"))?;
+        git.command(target_path)
+            .args(["symbolic-ref", "HEAD", &format!("refs/heads/{branch}")])
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("setting initial branch {branch} in {target_path} failed"))?;
+    }
+
+    Ok(())
+}
+
+/// Check whether `path` is a git repository without commits or with trenza move commits.
+fn looks_like_joined_repo(git: &GitRunner, path: &str, move_message_template: &str) -> bool {
+    if !is_repo_dir(Path::new(path)) {
+        return false;
+    }
+
+    let Ok(head) = git
+        .command(path)
+        .args(["rev-parse", "--verify", "--quiet", "HEAD"])
+        .output()
+    else {
+        return false;
+    };
+    if !head.status.success() {
+        // A repository without any commits is left behind by failed runs, which wrote the state
+        // file before any content. Ignored files would not show up in the status.
+        let only_git_dir = fs::read_dir(path).is_ok_and(|mut entries| {
+            entries.all(|entry| entry.is_ok_and(|entry| entry.file_name() == ".git"))
+        });
+        if !only_git_dir || !Path::new(path).join(STATE_FILE).is_file() {
+            return false;
+        }
+        return git
+            .command(path)
+            .args(["status", "--porcelain", "--untracked-files=all"])
+            .output()
+            .to_anyhow()
+            .is_ok_and(|status| status.stdout.is_empty());
+    }
+
+    let Ok(output) = git
+        .command(path)
+        .args(["log", "--format=%s"])
+        .output()
+        .to_anyhow()
+    else {
+        return false;
+    };
+
+    // Match the subject line of move commits with any repository name.
+    let subject = move_message_template.lines().next().unwrap_or_default();
+    let pattern = regex::escape(subject).replace(r"\{repo\}", ".+");
+    let move_commit = Regex::new(&format!("^{pattern}$")).unwrap();
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|subject| move_commit.is_match(subject) || subject == INITIAL_COMMIT_MESSAGE)
+}
+
+/// Check whether `path` is a git repository, either with a working tree or bare.
+fn is_repo_dir(path: &Path) -> bool {
+    path.join(".git").is_dir() || (path.join("HEAD").is_file() && path.join("objects").is_dir())
+}
+
+/// Check up front that git can be run and every repository is clean and has a branch to merge.
+///
+/// All problems are collected and reported together.
+fn preflight(
+    entries: &[MergeEntry],
+    root: &str,
+    existing_target: Option<&str>,
+    options: &MergeOptions,
+) -> Result<()> {
+    check_git(&options.git, root)?;
+
+    let problems: Vec<_> = check_repos(entries, existing_target, options)?
+        .iter()
+        .flat_map(|check| {
+            check
+                .problems
+                .iter()
+                .map(|problem| format!("{}: {problem}", check.repo))
+        })
+        .collect();
+
+    if !problems.is_empty() && options.continue_on_error {
+        // The repositories fail during the merge and are skipped there.
+        for problem in problems.iter() {
+            warn!("Preflight check failed for {problem}");
+        }
+    } else if !problems.is_empty() {
+        bail!(
+            "found {} problems, pass --skip-preflight to merge anyway:\n  {}",
+            problems.len(),
+            problems.join("\n  ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Check that the git executable of `git` can be run, failing with [`GitNotFound`] if it does not
+/// exist.
+pub fn check_git_installed(git: &GitRunner) -> Result<()> {
+    check_git(git, ".")
+}
+
+fn check_git(git: &GitRunner, root: &str) -> Result<()> {
+    match git.command(root).arg("--version").output() {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(GitNotFound {
+            binary: git.binary().to_owned(),
+        }
+        .into()),
+        output => output
+            .to_anyhow()
+            .with_context(|| "failed to run git")
+            .map(drop),
+    }
+}
+
+/// Branch of a repository to merge with the subdirectory it is placed in.
+struct MergeEntry<'a> {
+    repo_path: PathBuf,
+    /// Path of the repository relative to the root.
+    repo_name: String,
+    subdir: String,
+    /// Branch requested for the repository, merged without fallbacks.
+    branch: Option<String>,
+    /// Branch of [`MergeOptions::extra_branches`], `None` for the branch of the repository.
+    extra_branch: Option<&'a str>,
+    /// Repository which already uses `subdir`, never set with [`OnCollision::Suffix`].
+    collides_with: Option<String>,
+}
+
+/// Specs of repositories discovered below `root`, named by their path relative to the root.
+fn discovered_specs(
+    repos: &[PathBuf],
+    root: &str,
+    options: &MergeOptions,
+) -> Result<Vec<RepoSpec>> {
+    repos
+        .iter()
+        .map(|repo_path| {
+            let repo_name = relative_repo_name(repo_path, root)?;
+            Ok(RepoSpec {
+                path: repo_path.clone(),
+                branch: options.branch_map.get(&repo_name).cloned(),
+                subdir: Some(repo_subdir(&repo_name, options)),
+                name: Some(repo_name),
+            })
+        })
+        .collect()
+}
+
+/// Branches of `specs` to merge in merge order, extra branches right after their repository.
+///
+/// Collisions of subdirectories are resolved with a counter for [`OnCollision::Suffix`] and
+/// recorded in the later branch otherwise.
+fn merge_entries(specs: Vec<RepoSpec>, options: &MergeOptions) -> Vec<MergeEntry<'_>> {
+    let mut entries = Vec::new();
+    for spec in specs {
+        let subdir = spec
+            .subdir
+            .as_deref()
+            .map(|subdir| subdir.trim_matches('/').to_owned());
+        let repo_name = spec.name.or_else(|| subdir.clone()).unwrap_or_else(|| {
+            spec.path
+                .file_name()
+                .unwrap_or(spec.path.as_os_str())
+                .to_string_lossy()
+                .into_owned()
+        });
+        // Flattened repositories all go to the root, which does not count as a collision.
+        let subdir = if options.flatten {
+            FLATTEN_SUBDIR.to_owned()
+        } else {
+            prefixed_subdir(subdir.as_deref().unwrap_or(&repo_name), options)
+        };
+        entries.push(MergeEntry {
+            subdir,
+            repo_path: spec.path.clone(),
+            repo_name: repo_name.clone(),
+            branch: spec.branch,
+            extra_branch: None,
+            collides_with: None,
+        });
+        for extra in options
+            .extra_branches
+            .iter()
+            .filter(|extra| extra.repo == repo_name)
+        {
+            entries.push(MergeEntry {
+                repo_path: spec.path.clone(),
+                repo_name: repo_name.clone(),
+                subdir: prefixed_subdir(extra.subdir.trim_matches('/'), options),
+                branch: None,
+                extra_branch: Some(&extra.branch),
+                collides_with: None,
+            });
+        }
+    }
+
+    if options.flatten {
+        return entries;
+    }
+
+    // Branches placed in the same subdirectory would be moved into each other.
+    let mut subdirs: HashMap<String, String> = HashMap::new();
+    for entry in entries.iter_mut() {
+        if let Some(other) = subdirs.get(&entry.subdir) {
+            if options.on_collision != OnCollision::Suffix {
+                entry.collides_with = Some(other.clone());
+                continue;
+            }
+
+            let mut counter = 2;
+            while subdirs.contains_key(&format!("{}-{counter}", entry.subdir)) {
+                counter += 1;
+            }
+            let subdir = format!("{}-{counter}", entry.subdir);
+            info!(
+                "Placing repo {} in {subdir}, {} is already used by repo {other}",
+                entry.repo_name, entry.subdir
+            );
+            entry.subdir = subdir;
+        }
+        subdirs.insert(entry.subdir.clone(), entry.repo_name.clone());
+    }
+
+    entries
+}
+
+/// Fail if any of `entries` would be placed in a subdirectory used by another one.
+fn validate_no_collisions(entries: &[MergeEntry]) -> Result<()> {
+    let collisions: Vec<_> = entries
+        .iter()
+        .filter_map(|entry| {
+            let other = entry.collides_with.as_ref()?;
+            Some(format!(
+                "{} and {other} in {}",
+                entry.repo_name, entry.subdir
+            ))
+        })
+        .collect();
+
+    if !collisions.is_empty() {
+        bail!(
+            "repositories would be placed in the same subdirectory: {}; map them to other \
+             subdirectories or pass --on-collision suffix or skip",
+            collisions.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Check that the branches of `entries` can be merged, with one check for every branch.
+///
+/// Conflicts are checked against the content of `existing_target` if it is set.
+fn check_repos(
+    entries: &[MergeEntry],
+    existing_target: Option<&str>,
+    options: &MergeOptions,
+) -> Result<Vec<RepoCheck>> {
+    let git = &options.git;
+    let mut manifest_re = manifest_regex(&options.manifest_pattern)?;
+    let mut checks = Vec::new();
+    // Extra branches of repositories which cannot be merged are not checked.
+    let mut skipped_repo = None;
+    let mut oversized = Vec::new();
+
+    for entry in entries {
+        let repo_path = &entry.repo_path;
+        let mut check = RepoCheck {
+            repo: entry.repo_name.clone(),
+            branch: None,
+            subdir: entry.subdir.clone(),
+            empty: false,
+            problems: Vec::new(),
+        };
+        if let Some(other) = &entry.collides_with {
+            if options.on_collision == OnCollision::Error {
+                check.problems.push(format!(
+                    "subdirectory {} is already used by repo {other}",
+                    entry.subdir
+                ));
+            }
+        }
+
+        if let Some(extra_branch) = entry.extra_branch {
+            if skipped_repo == Some(&entry.repo_name) {
+                continue;
+            }
+            match resolve_requested_branch(git, repo_path, extra_branch, &[]) {
+                Ok(branch) => check.branch = Some(branch),
+                Err(err) => check.problems.push(format!("{err:#}")),
+            }
+            checks.push(check);
+            continue;
+        }
+
+        match is_empty_repo(git, repo_path) {
+            Ok(false) => (),
+            Ok(true) if options.fail_on_empty => {
+                check.problems.push("repository has no commits".to_owned());
+                checks.push(check);
+                skipped_repo = Some(&entry.repo_name);
+                continue;
+            }
+            // Empty repositories are skipped during the merge.
+            Ok(true) => {
+                check.empty = true;
+                checks.push(check);
+                skipped_repo = Some(&entry.repo_name);
+                continue;
+            }
+            Err(err) => {
+                check.problems.push(format!("{err:#}"));
+                checks.push(check);
+                skipped_repo = Some(&entry.repo_name);
+                continue;
+            }
+        }
+
+        if !options.stash_dirty {
+            match is_dirty(git, repo_path) {
+                Ok(false) => (),
+                Ok(true) => check.problems.push(
+                    "uncommitted changes would break the checkout of the branch to merge"
+                        .to_owned(),
+                ),
+                Err(err) => check.problems.push(format!("{err:#}")),
+            }
+        }
+
+        if let Some(max_size) = options.max_repo_size {
+            match repo_size(git, repo_path) {
+                Ok(size) if size > max_size => {
+                    if options.strict {
+                        check.problems.push(format!(
+                            "repository objects take {}, more than the maximum of {}",
+                            format_size(size),
+                            format_size(max_size)
+                        ));
+                    }
+                    oversized.push((size, entry.repo_name.clone()));
+                }
+                Ok(_) => (),
+                Err(err) => check.problems.push(format!("{err:#}")),
+            }
+        }
+
+        let branch = match (&entry.branch, &options.branch) {
+            (Some(branch), _) => resolve_requested_branch(git, repo_path, branch, &[]),
+            (None, Some(branch)) => {
+                resolve_requested_branch(git, repo_path, branch, &options.branch_fallbacks)
+            }
+            (None, None) => resolve_manifest_branch(
+                git,
+                repo_path,
+                &mut manifest_re,
+                options.fallback_default_branch,
+            )
+            .map(|(branch, _)| branch),
+        };
+        match branch {
+            Ok(branch) => check.branch = Some(branch),
+            Err(err) => check.problems.push(format!("{err:#}")),
+        }
+        checks.push(check);
+    }
+
+    if !oversized.is_empty() {
+        oversized.sort_by(|a, b| b.cmp(a));
+        let largest: Vec<_> = oversized
+            .iter()
+            .take(MAX_REPORTED_OVERSIZED_REPOS)
+            .map(|(size, repo)| format!("{repo} ({})", format_size(*size)))
+            .collect();
+        warn!(
+            "{} repositories are larger than {}, consider cleaning them with git filter-repo \
+             before joining them; largest: {}",
+            oversized.len(),
+            format_size(options.max_repo_size.unwrap_or_default()),
+            largest.join(", ")
+        );
+    }
+
+    if options.check_conflicts {
+        check_path_conflicts(git, entries, &mut checks, existing_target)?;
+    }
+
+    Ok(checks)
+}
+
+/// Add problems to `checks` for paths of the joined repository which several branches, or a
+/// branch and `existing_target`, would contain.
+///
+/// Only branches whose subdirectory overlaps with another one or with the target are listed, as
+/// branches in distinct subdirectories cannot conflict.
+fn check_path_conflicts(
+    git: &GitRunner,
+    entries: &[MergeEntry],
+    checks: &mut [RepoCheck],
+    existing_target: Option<&str>,
+) -> Result<()> {
+    // Subdirectories overlap if one contains the other.
+    let overlaps = |a: &str, b: &str| {
+        a == b || a.starts_with(&format!("{b}/")) || b.starts_with(&format!("{a}/"))
+    };
+    let subdirs: Vec<_> = checks
+        .iter()
+        .filter(|check| check.branch.is_some() && !check.empty)
+        .map(|check| check.subdir.clone())
+        .collect();
+
+    // Paths of the joined repository mapped to whoever brings them in.
+    let mut files: HashMap<String, String> = HashMap::new();
+    let mut dirs: HashMap<String, String> = HashMap::new();
+    let mut claim = |path: String, owner: &str| -> Option<String> {
+        let conflict = files
+            .get(&path)
+            .or_else(|| dirs.get(&path))
+            .or_else(|| {
+                // A file of another branch where this one has a directory.
+                path.match_indices('/')
+                    .find_map(|(idx, _)| files.get(&path[..idx]))
+            })
+            .filter(|other| *other != owner)
+            .cloned();
+
+        for (idx, _) in path.match_indices('/') {
+            dirs.entry(path[..idx].to_owned())
+                .or_insert_with(|| owner.to_owned());
+        }
+        files.entry(path).or_insert_with(|| owner.to_owned());
+
+        conflict
+    };
+
+    if let Some(target) = existing_target {
+        let output = git
+            .command(target)
+            .args(["ls-tree", "-r", "--name-only", "HEAD"])
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("listing the content of {target} failed"))?;
+        for path in String::from_utf8_lossy(&output.stdout).lines() {
+            claim(path.to_owned(), "the existing target");
+        }
+    }
+
+    for check in checks.iter_mut() {
+        let Some(branch) = &check.branch else {
+            continue;
+        };
+        let nested = subdirs
+            .iter()
+            .filter(|subdir| overlaps(subdir, &check.subdir))
+            .count()
+            > 1;
+        if check.empty || (!nested && existing_target.is_none()) {
+            continue;
+        }
+        let Some(entry) = entries
+            .iter()
+            .find(|entry| entry.repo_name == check.repo && entry.subdir == check.subdir)
+        else {
+            continue;
+        };
+
+        let output = match git
+            .command(&entry.repo_path)
+            .args(["ls-tree", "-r", "--name-only", branch])
+            .output()
+            .to_anyhow()
+        {
+            Ok(output) => output,
+            Err(err) => {
+                check
+                    .problems
+                    .push(format!("listing the content of {branch} failed: {err:#}"));
+                continue;
+            }
+        };
+
+        let owner = format!("repo {} ({branch})", check.repo);
+        let mut conflicts = Vec::new();
+        for path in String::from_utf8_lossy(&output.stdout).lines() {
+            let path = if check.subdir == FLATTEN_SUBDIR {
+                path.to_owned()
+            } else {
+                format!("{}/{path}", check.subdir)
+            };
+            if let Some(other) = claim(path.clone(), &owner) {
+                conflicts.push(format!("{path} with {other}"));
+            }
+        }
+
+        if !conflicts.is_empty() {
+            let mut problem = format!(
+                "merge would conflict in {} paths: {}",
+                conflicts.len(),
+                conflicts[..conflicts.len().min(MAX_REPORTED_CONFLICTS)].join(", ")
+            );
+            if conflicts.len() > MAX_REPORTED_CONFLICTS {
+                problem.push_str(", ...");
+            }
+            check.problems.push(problem);
+        }
+    }
+
+    Ok(())
+}
+
+/// Size in bytes of the loose and packed objects of a repository.
+fn repo_size(git: &GitRunner, repo_path: &Path) -> Result<u64> {
+    let output = git
+        .command(repo_path)
+        .args(["count-objects", "-v"])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("counting objects of {} failed", repo_path.display()))?;
+
+    // Sizes are reported in KiB.
+    let mut size_kib = 0;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some(size) = line
+            .strip_prefix("size: ")
+            .or_else(|| line.strip_prefix("size-pack: "))
+        {
+            size_kib += size.trim().parse::<u64>().with_context(|| {
+                format!("invalid object size {size} of {}", repo_path.display())
+            })?;
+        }
+    }
+
+    Ok(size_kib * 1024)
+}
+
+/// Format `bytes` with a binary unit, e.g. `1.5 GiB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Merge the branches of `entries` into the joined repository at `target_path`.
+///
+/// The merge happens in three phases:
+///
+/// 1. Every repository gets its branch prepared and is added as remote of the joined repository.
+/// 2. All remotes are fetched, concurrently if configured.
+/// 3. The repositories are merged one after another. With [`MergeStrategy::Move`], the fetched
+///    branch is checked out in a temporary worktree, where its content is moved to the
+///    subdirectory in the move commit. Only the move commit is merged into the joined
+///    repository, so repository content never lands at its root and cannot collide with
+///    top-level paths of other repositories or the target.
+///
+/// Afterwards, root files are combined, empty directories pruned and the post-merge hook run
+/// as configured, before the remotes are cleaned up. Kept remotes are returned with the path of
+/// their source repository.
+fn merge_repos(
+    entries: Vec<MergeEntry>,
+    target_path: &str,
+    options: &MergeOptions,
+) -> Result<(Vec<RepoReport>, BTreeMap<String, PathBuf>)> {
+    let git = &options.git;
+    let fallback = options.fallback_default_branch;
+    let mut reports = Vec::new();
+    let deadline = options
+        .max_duration
+        .map(|max_duration| Instant::now() + max_duration);
+
+    let mut state = if options.resume && !options.dry_run {
+        resume_state(git, target_path)?
+    } else {
+        MergeState::default()
+    };
+    if !options.resume && !options.dry_run && !options.no_commit {
+        // Failures before the first merge can be resumed as well.
+        state.initial_head = current_head(git, target_path);
+        state.save(target_path)?;
+    }
+
+    // In dry-run mode, branches are only resolved without checking anything out.
+    let fallbacks = &options.branch_fallbacks;
+    let mut prepare_branch = match (options.branch.clone(), options.dry_run) {
+        (Some(branch), false) => Box::new(move |repo_path: &PathBuf| {
+            prepare_requested_branch(git, repo_path, &branch, fallbacks).map(|b| (b, None))
+        })
+            as Box<dyn FnMut(&PathBuf) -> Result<(String, Option<String>)>>,
+        (Some(branch), true) => Box::new(move |repo_path: &PathBuf| {
+            resolve_requested_branch(git, repo_path, &branch, fallbacks).map(|b| (b, None))
+        })
+            as Box<dyn FnMut(&PathBuf) -> Result<(String, Option<String>)>>,
+        (None, false) => {
+            let mut manifest_re = manifest_regex(&options.manifest_pattern)?;
+            Box::new(move |repo_path: &PathBuf| {
+                prepare_manifest_branch(git, repo_path, &mut manifest_re, fallback)
+            }) as Box<dyn FnMut(&PathBuf) -> Result<(String, Option<String>)>>
+        }
+        (None, true) => {
+            let mut manifest_re = manifest_regex(&options.manifest_pattern)?;
+            Box::new(move |repo_path: &PathBuf| {
+                resolve_manifest_branch(git, repo_path, &mut manifest_re, fallback)
+            }) as Box<dyn FnMut(&PathBuf) -> Result<(String, Option<String>)>>
+        }
+    };
+
+    // Branch preparation and adding remotes happens up front so that fetches can run concurrently.
+    let mut prepared = Vec::new();
+    let mut resumed = Vec::new();
+    let mut lfs_imported = false;
+    // Remote names in the joined repository mapped to the repositories they were added for.
+    let mut remote_names = HashMap::new();
+    if options.into_existing && !options.resume && !options.dry_run && !options.no_fetch {
+        // Remotes of the existing repository must be left alone.
+        let output = git
+            .command(target_path)
+            .arg("remote")
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("listing remotes of {target_path} failed"))?;
+        for remote in String::from_utf8_lossy(&output.stdout).lines() {
+            remote_names.insert(remote.to_owned(), format!("existing remote {remote}"));
+        }
+    }
+
+    let total = entries.len();
+
+    for (index, entry) in entries.into_iter().enumerate() {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(stop_at_deadline(
+                &state,
+                &prepared,
+                target_path,
+                options,
+                index,
+                total,
+            ));
+        }
+
+        let MergeEntry {
+            repo_path,
+            repo_name,
+            subdir,
+            branch,
+            extra_branch,
+            collides_with,
+        } = entry;
+        let repo_name = &repo_name;
+        let _scope = RepoScope::enter(repo_name);
+        debug!("Preparing repo {repo_name} for {subdir}");
+
+        let progress = RepoProgress {
+            repo: repo_name.to_owned(),
+            index: index + 1,
+            total,
+        };
+        report_progress(options, MergeEvent::RepoStarted(progress.clone()));
+
+        if let Some(other) = collides_with {
+            warn!("Skipping repo {repo_name}, its subdirectory {subdir} is already used by repo {other}");
+            reports.push((
+                progress.index,
+                RepoReport {
+                    source: repo_path,
+                    branch: extra_branch.unwrap_or_default().to_owned(),
+                    subdir,
+                    commit: None,
+                    status: RepoStatus::Skipped,
+                    error: None,
+                    verified: false,
+                },
+            ));
+            report_progress(
+                options,
+                MergeEvent::RepoFinished(progress, RepoStatus::Skipped),
+            );
+            continue;
+        }
+
+        if let Some(completed) = state.get(repo_name, &subdir) {
+            debug!("Repo {repo_name} was merged before, skipping it");
+            // Reserve the remote name so that remaining repositories get the same ones as before.
+            let remote =
+                unique_remote_name(repo_name, &options.remote_name_template, &mut remote_names);
+
+            reports.push((
+                progress.index,
+                RepoReport {
+                    source: repo_path.clone(),
+                    branch: completed.branch.clone(),
+                    subdir: completed.subdir.clone(),
+                    commit: completed.commit.clone(),
+                    status: RepoStatus::Merged,
+                    error: None,
+                    verified: false,
+                },
+            ));
+            report_progress(
+                options,
+                MergeEvent::RepoFinished(progress.clone(), RepoStatus::Merged),
+            );
+
+            // Its remote was left behind by the interrupted merge.
+            resumed.push(PreparedRepo {
+                name: repo_name.to_owned(),
+                remote,
+                path: repo_path.clone(),
+                branch: completed.branch.clone(),
+                tag: None,
+                subdir,
+                progress,
+                stashed_from: None,
+            });
+            continue;
+        }
+
+        // Stashed changes have to be restored if preparing the repository fails afterwards.
+        let mut stashed_from = None;
+        let res = (|| -> Result<()> {
+            if is_empty_repo(git, &repo_path)? {
+                if options.fail_on_empty {
+                    bail!("repo {repo_name} has no commits");
+                }
+                warn!("Skipping repo {repo_name} without commits");
+
+                reports.push((
+                    progress.index,
+                    RepoReport {
+                        source: repo_path.clone(),
+                        branch: String::new(),
+                        subdir: subdir.clone(),
+                        commit: None,
+                        status: RepoStatus::Skipped,
+                        error: None,
+                        verified: false,
+                    },
+                ));
+                report_progress(
+                    options,
+                    MergeEvent::RepoFinished(progress.clone(), RepoStatus::Skipped),
+                );
+                return Ok(());
+            }
+
+            stashed_from = if options.dry_run {
+                None
+            } else {
+                stash_dirty_changes(git, &repo_path, options.stash_dirty)
+                    .with_context(|| format!("repo {repo_name} has uncommitted changes"))?
+            };
+
+            let requested_branch = extra_branch.or(branch.as_deref());
+            let (merge_branch, tag) = match requested_branch {
+                Some(branch) if options.dry_run => {
+                    resolve_requested_branch(git, &repo_path, branch, &[]).map(|b| (b, None))
+                }
+                Some(branch) => {
+                    prepare_requested_branch(git, &repo_path, branch, &[]).map(|b| (b, None))
+                }
+                None => prepare_branch(&repo_path),
+            }
+            .with_context(|| format!("branch preparation for repo {repo_name} failed"))?;
+            debug!("Using merge branch {merge_branch} in source repository");
+
+            if options.dry_run {
+                reports.push((
+                    progress.index,
+                    RepoReport {
+                        source: repo_path.clone(),
+                        branch: merge_branch,
+                        subdir: subdir.clone(),
+                        commit: None,
+                        status: RepoStatus::Planned,
+                        error: None,
+                        verified: false,
+                    },
+                ));
+                report_progress(
+                    options,
+                    MergeEvent::RepoFinished(progress.clone(), RepoStatus::Planned),
+                );
+                return Ok(());
+            }
+
+            if let Some(hook) = &options.pre_merge_hook {
+                run_hook(
+                    "pre-merge",
+                    hook,
+                    &repo_path,
+                    &[(REPO_ENV, repo_name), (BRANCH_ENV, &merge_branch)],
+                )
+                .with_context(|| format!("pre-merge hook for repo {repo_name} failed"))?;
+            }
+
+            if uses_lfs(git, &repo_path, &merge_branch) {
+                match options.lfs {
+                    LfsMode::Keep => warn!(
+                        "Repo {repo_name} uses git LFS, only its pointer files are merged, pass \
+                         --lfs fetch to import the content"
+                    ),
+                    LfsMode::Fetch => {
+                        import_lfs_objects(git, &repo_path, &merge_branch, target_path)
+                            .with_context(|| {
+                                format!("importing LFS objects of repo {repo_name} failed")
+                            })?;
+                        lfs_imported = true;
+                    }
+                }
+            }
+
+            let remote =
+                unique_remote_name(repo_name, &options.remote_name_template, &mut remote_names);
+            debug!("Using remote name {remote} for repo {repo_name}");
+
+            if options.no_fetch {
+                let remote_branch = format!("refs/remotes/{remote}/{merge_branch}");
+                let fetched = git
+                    .command(target_path)
+                    .args(["rev-parse", "--verify", "--quiet", &remote_branch])
+                    .output()
+                    .is_ok_and(|output| output.status.success());
+                if !fetched {
+                    bail!(
+                        "{remote_branch} for repo {repo_name} is missing, fetch it or merge \
+                         without --no-fetch"
+                    );
+                }
+
+                prepared.push(PreparedRepo {
+                    name: repo_name.to_owned(),
+                    remote,
+                    path: repo_path.clone(),
+                    branch: merge_branch,
+                    tag,
+                    subdir: subdir.clone(),
+                    progress: progress.clone(),
+                    stashed_from: stashed_from.take(),
+                });
+                return Ok(());
+            }
+
+            if options.resume {
+                // The interrupted merge may have added the remote already.
+                let _ = git
+                    .command(target_path)
+                    .args(["remote", "remove", &remote])
+                    .output();
+            }
+
+            let fetch_path = if options.strategy == MergeStrategy::FilterRepo {
+                rewrite_history(
+                    git,
+                    &repo_path,
+                    &merge_branch,
+                    &subdir,
+                    &progress.index.to_string(),
+                    target_path,
+                )
+                .with_context(|| format!("rewriting history of repo {repo_name} failed"))?
+            } else {
+                repo_path.clone()
+            };
+
+            let remote_url = if options.relative_remotes {
+                relative_remote_path(&fetch_path, target_path)?
+            } else {
+                fetch_path
+            };
+            git.command(target_path)
+                .args(["remote", "add", &remote])
+                .arg(&remote_url)
+                .output()
+                .to_anyhow()
+                .with_context(|| format!("remote add for repo {repo_name} failed"))?;
+
+            prepared.push(PreparedRepo {
+                name: repo_name.to_owned(),
+                remote,
+                path: repo_path.clone(),
+                branch: merge_branch,
+                tag,
+                subdir: subdir.clone(),
+                progress: progress.clone(),
+                stashed_from: stashed_from.take(),
+            });
+            Ok(())
+        })();
+
+        if let Err(err) = res {
+            if !options.continue_on_error {
+                return Err(err);
+            }
+            warn!("Skipping repo {repo_name} after error: {err:#}");
+
+            // Adding the remote is the last step, so the joined repository is untouched.
+            if let Some(original_head) = &stashed_from {
+                restore_stashed_changes(git, &repo_path, repo_name, original_head);
+            }
+            reports.push((progress.index, failed_report(&repo_path, "", &subdir, &err)));
+            report_progress(
+                options,
+                MergeEvent::RepoFinished(progress, RepoStatus::Failed),
+            );
+        }
+    }
+
+    let mut fetch_failures = if options.no_fetch {
+        HashMap::new()
+    } else {
+        // Repositories left unfetched at the deadline are stopped at in the merge loop.
+        fetch_repos(&prepared, target_path, options, deadline)?
+    };
+
+    // Repositories staged without committing them, with their source trailers.
+    let mut staged = Vec::new();
+    // Merges have to be sequential since they share the working tree of the joined repository.
+    for (index, repo) in prepared.iter().enumerate() {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(stop_at_deadline(
+                &state,
+                &prepared,
+                target_path,
+                options,
+                repo.progress.index - 1,
+                repo.progress.total,
+            ));
+        }
+
+        let repo_name = repo.name.as_str();
+        let _scope = RepoScope::enter(repo_name);
+        debug!("Merging repo {repo_name}");
+
+        // Every repository is merged as a transaction, so that failures leave the joined
+        // repository as it was after the previous repository.
+        let head = current_head(git, target_path);
+        let res = match fetch_failures.remove(&index) {
+            Some(err) => Err(err),
+            None => (|| -> Result<RepoReport> {
+                let remote_branch = format!("{}/{}", repo.remote, repo.branch);
+                let commit = git
+                    .command(target_path)
+                    .args(["rev-parse", &remote_branch])
+                    .output()
+                    .to_anyhow()
+                    .with_context(|| format!("failed to resolve fetched branch {remote_branch}"))?;
+
+                let commit = String::from_utf8_lossy(&commit.stdout).trim().to_owned();
+                let source_trailer = format!(
+                    "Trenza-Source: {repo_name}@{commit} (branch {})",
+                    repo.branch
+                );
+
+                let mut report = RepoReport {
+                    source: repo.path.clone(),
+                    branch: repo.branch.clone(),
+                    subdir: repo.subdir.clone(),
+                    commit: Some(commit),
+                    status: RepoStatus::Skipped,
+                    error: None,
+                    verified: false,
+                };
+
+                if options.no_commit {
+                    // Git only supports merges into a branch with commits.
+                    ensure_initial_commit(git, target_path)?;
+                    if read_tree_into(git, repo, &report.subdir, target_path, options)? {
+                        staged.push((repo_name, source_trailer));
+                        report.status = RepoStatus::Staged;
+                    } else {
+                        warn!("Skipped repository {repo_name} because of merge conflicts");
+                    }
+                    return Ok(report);
+                }
+
+                let merged = match options.strategy {
+                    _ if options.flatten => {
+                        // Without a move commit, the merge commit records the source of the
+                        // repository, so the branch needs a commit to merge into.
+                        ensure_initial_commit(git, target_path)?;
+                        let message = match &options.merge_message_template {
+                            Some(template) => template
+                                .replace("{repo}", repo_name)
+                                .replace("{branch}", &repo.branch),
+                            None => format!("Merge {remote_branch} into the root"),
+                        };
+                        merge_repo(
+                            git,
+                            repo,
+                            &remote_branch,
+                            Some(&format!("{message}\n\n{source_trailer}")),
+                            target_path,
+                            options.on_conflict,
+                        )?
+                    }
+                    _ if options.squash_history => {
+                        let message = format!(
+                            "{}\n\n{source_trailer}",
+                            options.move_message_template.replace("{repo}", repo_name)
+                        );
+                        squash_add(git, repo, &report.subdir, &message, target_path, options)?
+                    }
+                    MergeStrategy::Move => {
+                        let moved = move_in_worktree(
+                            git,
+                            &remote_branch,
+                            repo_name,
+                            &report.subdir,
+                            &source_trailer,
+                            target_path,
+                            options,
+                        )?;
+                        report_progress(options, MergeEvent::Moved(repo.progress.clone()));
+
+                        // Without any commit in the joined repository, the move commit is
+                        // fast-forwarded to instead.
+                        let message = match &options.merge_message_template {
+                            Some(template) => template
+                                .replace("{repo}", repo_name)
+                                .replace("{branch}", &repo.branch),
+                            None => format!("Merge {remote_branch} into {}", report.subdir),
+                        };
+                        let message = head.is_some().then_some(message.as_str());
+                        merge_repo(git, repo, &moved, message, target_path, options.on_conflict)?
+                    }
+                    MergeStrategy::FilterRepo => {
+                        // The content is in its subdirectory already, the merge commit takes the place of
+                        // the move commit. It can't be a fast-forward, so the branch needs a commit.
+                        ensure_initial_commit(git, target_path)?;
+                        let message = format!(
+                            "{}\n\n{source_trailer}",
+                            options.move_message_template.replace("{repo}", repo_name)
+                        );
+                        merge_repo(
+                            git,
+                            repo,
+                            &remote_branch,
+                            Some(&message),
+                            target_path,
+                            options.on_conflict,
+                        )?
+                    }
+                    MergeStrategy::Subtree => {
+                        let message = format!(
+                            "{}\n\n{source_trailer}",
+                            options.move_message_template.replace("{repo}", repo_name)
+                        );
+                        subtree_add(git, repo, &report.subdir, &message, target_path, options)?
+                    }
+                };
+                if !merged {
+                    warn!("Skipped repository {repo_name} because of merge conflicts");
+                    return Ok(report);
+                }
+                report_progress(options, MergeEvent::Merged(repo.progress.clone()));
+
+                // Submodule handling changes the content on purpose, so it is verified before.
+                if options.verify {
+                    verify_subdir(git, &remote_branch, &report.subdir, target_path, options)
+                        .with_context(|| format!("verification of repo {repo_name} failed"))?;
+                    report.verified = true;
+                }
+
+                if options.annotate_commits {
+                    git.command(target_path)
+                        .args(["notes", "--ref", NOTES_REF, "add", "--force", "-m"])
+                        .arg(&source_trailer)
+                        .output()
+                        .to_anyhow()
+                        .with_context(|| format!("annotating merge of repo {repo_name} failed"))?;
+                }
+
+                handle_submodules(git, repo, &report.subdir, target_path, options.submodules)
+                    .with_context(|| format!("handling submodules of repo {repo_name} failed"))?;
+
+                let head = git
+                    .command(target_path)
+                    .args(["rev-parse", "HEAD"])
+                    .output()
+                    .to_anyhow()
+                    .with_context(|| {
+                        format!("failed to resolve HEAD after merging repo {repo_name}")
+                    })?;
+                state.completed.push(CompletedRepo {
+                    repo: repo_name.to_owned(),
+                    branch: repo.branch.clone(),
+                    subdir: report.subdir.clone(),
+                    commit: report.commit.clone(),
+                    head: String::from_utf8_lossy(&head.stdout).trim().to_owned(),
+                });
+                state.save(target_path)?;
+
+                info!(
+                    "Merged repository {repo_name} ({})",
+                    repo.path.to_string_lossy()
+                );
+
+                report.status = RepoStatus::Merged;
+                Ok(report)
+            })(),
+        };
+
+        let report = match res {
+            Ok(report) => report,
+            Err(err) => {
+                // A failed read-tree leaves the index untouched, while a rollback would drop the
+                // repositories staged before.
+                if !options.no_commit {
+                    rollback_merge(git, target_path, head.as_deref());
+                }
+                if !options.continue_on_error {
+                    return Err(err);
+                }
+                warn!("Skipping repo {repo_name} after error: {err:#}");
+                failed_report(&repo.path, &repo.branch, &repo.subdir, &err)
+            }
+        };
+        let status = report.status;
+        reports.push((repo.progress.index, report));
+        report_progress(
+            options,
+            MergeEvent::RepoFinished(repo.progress.clone(), status),
+        );
+    }
+
+    // Repositories skipped or failed while preparing are reported before the ones merged ahead
+    // of them, so the reports are sorted back into merge order.
+    reports.sort_by_key(|(index, _)| *index);
+    let reports: Vec<_> = reports.into_iter().map(|(_, report)| report).collect();
+
+    if !staged.is_empty() {
+        stage_pending_merge(&staged, &reports, target_path, options)?;
+    }
+
+    if lfs_imported {
+        checkout_lfs_objects(git, target_path)
+            .with_context(|| "checking out LFS objects in the joined repository failed")?;
+    }
+
+    let merged_subdirs: Vec<_> = reports
+        .iter()
+        .filter(|report| report.status == RepoStatus::Merged)
+        .map(|report| report.subdir.as_str())
+        .collect();
+
+    if options.merge_gitignore && !options.dry_run {
+        combine_root_file(
+            git,
+            ".gitignore",
+            &merged_subdirs,
+            target_path,
+            root_gitignore_pattern,
+            GITIGNORE_COMMIT_MESSAGE,
+        )
+        .with_context(|| "combining .gitignore files failed")?;
+    }
+
+    if options.rewrite_gitattributes && !options.dry_run {
+        combine_root_file(
+            git,
+            ".gitattributes",
+            &merged_subdirs,
+            target_path,
+            root_gitattributes_line,
+            GITATTRIBUTES_COMMIT_MESSAGE,
+        )
+        .with_context(|| "combining .gitattributes files failed")?;
+    }
+
+    if !options.dedup_root_files.is_empty() && !options.dry_run {
+        dedup_root_files(git, &options.dedup_root_files, &merged_subdirs, target_path)
+            .with_context(|| "moving identical files to the root failed")?;
+    }
+
+    if options.prune_empty_dirs && !options.dry_run {
+        prune_empty_dirs(git, target_path).with_context(|| "pruning empty directories failed")?;
+    }
+
+    // Remotes and stashes are cleaned up even if the hook fails.
+    let hook_result = match &options.post_merge_hook {
+        Some(hook) if !options.dry_run => {
+            let names: HashMap<_, _> = resumed
+                .iter()
+                .chain(prepared.iter())
+                .map(|repo| (repo.path.as_path(), repo.name.as_str()))
+                .collect();
+            run_post_merge_hook(hook, &reports, &names, target_path)
+        }
+        _ => Ok(()),
+    };
+
+    let mut remotes = BTreeMap::new();
+    if !options.keep_remotes {
+        cleanup(git, &resumed, target_path);
+        cleanup(git, &prepared, target_path);
+    } else if !options.dry_run {
+        for repo in resumed.iter().chain(prepared.iter()) {
+            remotes.insert(repo.remote.clone(), repo.path.clone());
+        }
+    }
+
+    for repo in prepared.iter() {
+        if let Some(original_head) = &repo.stashed_from {
+            restore_stashed_changes(git, &repo.path, &repo.name, original_head);
+        }
+    }
+
+    hook_result?;
+
+    Ok((reports, remotes))
+}
+
+/// Run `hook` with `sh -c` in the joined repository, passing the merged repositories of `reports`.
+///
+/// `names` maps the source repositories to their names.
+fn run_post_merge_hook(
+    hook: &str,
+    reports: &[RepoReport],
+    names: &HashMap<&Path, &str>,
+    target_path: &str,
+) -> Result<()> {
+    let mut merged_repos = Vec::new();
+    for report in reports {
+        if report.status != RepoStatus::Merged {
+            continue;
+        }
+        let Some(&repo) = names.get(report.source.as_path()) else {
+            continue;
+        };
+        // Extra branches of a repository follow its branch directly.
+        if merged_repos.last() != Some(&repo) {
+            merged_repos.push(repo);
+        }
+    }
+
+    run_hook(
+        "post-merge",
+        hook,
+        Path::new(target_path),
+        &[(MERGED_REPOS_ENV, &merged_repos.join("\n"))],
+    )
+}
+
+/// Run the `kind` hook `hook` with `sh -c` in `dir`, with the additional environment `envs`.
+fn run_hook(kind: &str, hook: &str, dir: &Path, envs: &[(&str, &str)]) -> Result<()> {
+    info!("Running {kind} hook in {}: {hook}", dir.display());
+    let status = shell_command(hook)
+        .current_dir(dir)
+        .envs(envs.iter().copied())
+        // Stdout is reserved for the merge report.
+        .stdout(io::stderr())
+        .status()
+        .with_context(|| format!("failed to run {kind} hook {hook}"))?;
+
+    if !status.success() {
+        bail!("{kind} hook {hook} failed with {status}");
+    }
+
+    Ok(())
+}
+
+/// Command running `script` with the shell of the platform.
+#[cfg(not(windows))]
+fn shell_command(script: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.args(["-c", script]);
+    command
+}
+
+/// Command running `script` with the shell of the platform.
+#[cfg(windows)]
+fn shell_command(script: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+
+    // cmd does not understand the escaping of quoted arguments, so the script is passed as is.
+    let mut command = Command::new("cmd");
+    command.arg("/C").raw_arg(script);
+    command
+}
+
+/// Load the state of an interrupted merge and reset the joined repository to the last completed
+/// repository, dropping partial changes of the repository which was interrupted.
+///
+/// Without completed repositories, the joined repository is reset to its commit before the merge.
+fn resume_state(git: &GitRunner, target_path: &str) -> Result<MergeState> {
+    let state = MergeState::load(target_path)
+        .with_context(|| format!("failed to resume merge into {target_path}"))?;
+
+    let head = match (state.completed.last(), &state.initial_head) {
+        (Some(last), _) => Some(&last.head),
+        (None, initial_head) => initial_head.as_ref(),
+    };
+
+    match head {
+        Some(head) => {
+            git.command(target_path)
+                .args(["reset", "--hard", head])
+                .output()
+                .to_anyhow()
+                .with_context(|| format!("failed to reset {target_path} to {head}"))?;
+        }
+        // A partial merge into a repository without commits cannot be reset.
+        None if current_head(git, target_path).is_some() => {
+            bail!(
+                "no repository was merged into {target_path} yet; start over with --force instead"
+            );
+        }
+        None => {}
+    }
+    git.command(target_path)
+        .args(["clean", "-fd"])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("failed to clean {target_path}"))?;
+
+    info!(
+        "Resuming merge into {target_path} after {} completed repositories",
+        state.completed.len()
+    );
+
+    Ok(state)
+}
+
+fn report_progress(options: &MergeOptions, event: MergeEvent) {
+    if let Some(handler) = &options.on_progress {
+        handler.report(event);
+    }
+}
+
+/// Add the branch of `repo` in `subdir` of the joined repository with a single merge commit.
+///
+/// Returns `false` if the repository was skipped because `subdir` already exists.
+fn subtree_add(
+    git: &GitRunner,
+    repo: &PreparedRepo,
+    subdir: &str,
+    message: &str,
+    target_path: &str,
+    options: &MergeOptions,
+) -> Result<bool> {
+    if Path::new(target_path).join(subdir).exists() {
+        match options.on_conflict {
+            OnConflict::Skip => return Ok(false),
+            OnConflict::Abort | OnConflict::Theirs => bail!(
+                "subdirectory {subdir} for repo {} already exists in the joined repository; \
+                 use --on-conflict skip to continue anyway",
+                repo.name
+            ),
+        }
+    }
+
+    // `git subtree` cannot add to a branch without commits.
+    ensure_initial_commit(git, target_path)?;
+
+    let remote_branch = format!("{}/{}", repo.remote, repo.branch);
+    git.command(target_path)
+        .args([
+            "subtree",
+            "add",
+            &format!("--prefix={subdir}"),
+            "-m",
+            message,
+        ])
+        .arg(&remote_branch)
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("subtree add of {remote_branch} failed"))
+        .map(|_| true)
+}
+
+/// Add the tree of the fetched branch of `repo` in `subdir` with a single commit, leaving out its
+/// history.
+fn squash_add(
+    git: &GitRunner,
+    repo: &PreparedRepo,
+    subdir: &str,
+    message: &str,
+    target_path: &str,
+    options: &MergeOptions,
+) -> Result<bool> {
+    if !read_tree_into(git, repo, subdir, target_path, options)? {
+        return Ok(false);
+    }
+
+    let remote_branch = format!("{}/{}", repo.remote, repo.branch);
+    git.command(target_path)
+        .args(["commit", "--allow-empty", "-m", message])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("commit of {remote_branch} failed"))?;
+
+    Ok(true)
+}
+
+/// Stage the tree of the fetched branch of `repo` in `subdir` of the joined repository.
+///
+/// Returns `false` if the subdirectory exists already and the repository is skipped.
+fn read_tree_into(
+    git: &GitRunner,
+    repo: &PreparedRepo,
+    subdir: &str,
+    target_path: &str,
+    options: &MergeOptions,
+) -> Result<bool> {
+    if Path::new(target_path).join(subdir).exists() {
+        match options.on_conflict {
+            OnConflict::Skip => return Ok(false),
+            OnConflict::Abort | OnConflict::Theirs => bail!(
+                "subdirectory {subdir} for repo {} already exists in the joined repository; \
+                 use --on-conflict skip to continue anyway",
+                repo.name
+            ),
+        }
+    }
+
+    let remote_branch = format!("{}/{}", repo.remote, repo.branch);
+    git.command(target_path)
+        .args([
+            "read-tree",
+            &format!("--prefix={subdir}/"),
+            "-u",
+            &remote_branch,
+        ])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("reading the tree of {remote_branch} failed"))?;
+
+    Ok(true)
+}
+
+/// Record the staged repositories as a merge in progress, so that committing the staged content
+/// creates a merge commit with their commits as parents.
+///
+/// `staged` holds the names and source trailers of the repositories.
+fn stage_pending_merge(
+    staged: &[(&str, String)],
+    reports: &[RepoReport],
+    target_path: &str,
+    options: &MergeOptions,
+) -> Result<()> {
+    let merge_heads: String = reports
+        .iter()
+        .filter(|report| report.status == RepoStatus::Staged)
+        .filter_map(|report| report.commit.as_deref())
+        .map(|commit| format!("{commit}\n"))
+        .collect();
+    let names: Vec<_> = staged.iter().map(|(name, _)| *name).collect();
+    let trailers: Vec<_> = staged.iter().map(|(_, trailer)| trailer.as_str()).collect();
+    let message = format!(
+        "{}\n\n{}\n",
+        options
+            .move_message_template
+            .replace("{repo}", &names.join(", ")),
+        trailers.join("\n")
+    );
+
+    let git_dir = Path::new(target_path).join(".git");
+    fs::write(git_dir.join("MERGE_HEAD"), merge_heads)
+        .and_then(|_| fs::write(git_dir.join("MERGE_MSG"), message))
+        .with_context(|| format!("failed to record the pending merge in {target_path}"))?;
+    info!(
+        "Staged {} repositories in {target_path}, review and commit them with `git commit`",
+        staged.len()
+    );
+
+    Ok(())
+}
+
+/// Create an empty commit in the joined repository unless it has commits already.
+fn ensure_initial_commit(git: &GitRunner, target_path: &str) -> Result<()> {
+    let head = git
+        .command(target_path)
+        .args(["rev-parse", "--verify", "--quiet", "HEAD"])
+        .output()
+        .with_context(|| "failed to execute process")?;
+    if head.status.success() {
+        return Ok(());
+    }
+
+    git.command(target_path)
+        .args(["commit", "--allow-empty", "-m", INITIAL_COMMIT_MESSAGE])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("initial commit in {target_path} failed"))
+        .map(drop)
+}
+
+/// Merge `rev` of `repo` into the joined repository, handling conflicts as requested.
+///
+/// Returns `false` if the repository was skipped because of conflicts.
+fn merge_repo(
+    git: &GitRunner,
+    repo: &PreparedRepo,
+    rev: &str,
+    message: Option<&str>,
+    target_path: &str,
+    on_conflict: OnConflict,
+) -> Result<bool> {
+    // The merged revision may be a bare commit, so messages also name the repository.
+    let merged = format!("{rev} of repo {}", repo.name);
+
+    let mut merge = git.command(target_path);
+    merge.args(["merge", rev, "--allow-unrelated-histories"]);
+    if let Some(message) = message {
+        merge.args(["--no-ff", "-m", message]);
+    }
+    let output = merge.output();
+
+    if output.as_ref().is_ok_and(|output| output.status.success()) {
+        return Ok(true);
+    }
+
+    let conflicts = conflicting_paths(git, target_path)?;
+    if conflicts.is_empty() {
+        // Not a conflict, report the original failure.
+        output
+            .to_anyhow()
+            .with_context(|| format!("merge of {merged} failed"))?;
+    }
+
+    match on_conflict {
+        OnConflict::Abort | OnConflict::Skip => {
+            git.command(target_path)
+                .args(["merge", "--abort"])
+                .output()
+                .to_anyhow()
+                .with_context(|| format!("aborting merge of {merged} failed"))?;
+
+            if on_conflict == OnConflict::Abort {
+                bail!(
+                    "merge of {merged} conflicted in paths: {}; \
+                     use --on-conflict theirs or skip to continue anyway",
+                    conflicts.join(", ")
+                );
+            }
+
+            Ok(false)
+        }
+        OnConflict::Theirs => {
+            for path in conflicts.iter() {
+                warn!(
+                    "Resolving conflict in {path} with the version of {}",
+                    repo.name
+                );
+
+                // Paths deleted in the merged repository cannot be checked out.
+                git.command(target_path)
+                    .args(["checkout", "--theirs", "--", path])
+                    .output()
+                    .to_anyhow()
+                    .and_then(|_| {
+                        git.command(target_path)
+                            .args(["add", "--", path])
+                            .output()
+                            .to_anyhow()
+                    })
+                    .or_else(|_| {
+                        git.command(target_path)
+                            .args(["rm", "--", path])
+                            .output()
+                            .to_anyhow()
+                    })
+                    .with_context(|| format!("resolving conflict in {path} failed"))?;
+            }
+
+            git.command(target_path)
+                .args(["commit", "--no-edit"])
+                .output()
+                .to_anyhow()
+                .with_context(|| format!("merge commit of {merged} failed"))?;
+
+            Ok(true)
+        }
+    }
+}
+
+/// Paths with unresolved conflicts in the repository at `path`.
+fn conflicting_paths(git: &GitRunner, path: &str) -> Result<Vec<String>> {
+    let output = git
+        .command(path)
+        .args(["diff", "--name-only", "--diff-filter=U"])
+        .output()
+        .to_anyhow()
+        .with_context(|| "listing conflicting paths failed")?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+/// Source repository which was added as remote to the joined repository.
+struct PreparedRepo {
+    /// Path relative to the root, determines the target subdirectory.
+    name: String,
+    /// Name of the remote in the joined repository.
+    remote: String,
+    /// Path of the source repository.
+    path: PathBuf,
+    /// Branch to merge from the source repository.
+    branch: String,
+    /// Tag pointed to by the manifest which the branch was created from.
+    tag: Option<String>,
+    /// Subdirectory of the joined repository for the branch.
+    subdir: String,
+    /// Position of the repository in merge order.
+    progress: RepoProgress,
+    /// Originally checked out branch or commit if uncommitted changes were stashed.
+    stashed_from: Option<String>,
+}
+
+/// Stop a merge at its deadline after `done` of `total` repositories.
+///
+/// If a repository was merged, the state and the remotes are left behind as after an
+/// interruption so that the merge can be resumed. Otherwise there is nothing to resume and the
+/// remotes added while preparing are removed.
+fn stop_at_deadline(
+    state: &MergeState,
+    prepared: &[PreparedRepo],
+    target_path: &str,
+    options: &MergeOptions,
+    done: usize,
+    total: usize,
+) -> anyhow::Error {
+    let git = &options.git;
+    let resumable = !state.completed.is_empty();
+
+    for repo in prepared {
+        if let Some(original_head) = &repo.stashed_from {
+            restore_stashed_changes(git, &repo.path, &repo.name, original_head);
+        }
+    }
+
+    if resumable {
+        if let Err(err) = state.save(target_path) {
+            return err;
+        }
+    } else if !options.no_fetch {
+        for repo in prepared {
+            let res = git
+                .command(target_path)
+                .args(["remote", "remove", &repo.remote])
+                .output()
+                .to_anyhow();
+            if let Err(err) = res {
+                warn!("Failed to remove remote {}: {err:#}", repo.remote);
+            }
+        }
+    }
+
+    DeadlineExceeded {
+        done,
+        total,
+        resumable,
+    }
+    .into()
+}
+
+/// Fetch the remotes of all prepared repositories using up to `jobs` concurrent fetches.
+///
+/// With [`MergeOptions::continue_on_error`], failed fetches are returned by the position of their
+/// repository instead of stopping all fetches. No further fetches are started after `deadline`.
+fn fetch_repos(
+    repos: &[PreparedRepo],
+    target_path: &str,
+    options: &MergeOptions,
+    deadline: Option<Instant>,
+) -> Result<HashMap<usize, anyhow::Error>> {
+    let next_repo = AtomicUsize::new(0);
+
+    // Shallow fetches all update `.git/shallow` of the joined repository and fail on its lock
+    // when run concurrently.
+    let jobs = match options.fetch_depth {
+        Some(_) => 1,
+        None => options.jobs,
+    };
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, repos.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| -> Result<Vec<(usize, anyhow::Error)>> {
+                    let mut failures = Vec::new();
+                    loop {
+                        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                            break;
+                        }
+                        let index = next_repo.fetch_add(1, Ordering::Relaxed);
+                        let Some(repo) = repos.get(index) else {
+                            break;
+                        };
+                        let _scope = RepoScope::enter(&repo.name);
+
+                        match fetch_repo_with_retries(repo, target_path, options) {
+                            Err(err) if options.continue_on_error => failures.push((index, err)),
+                            Err(err) => {
+                                // Keep other workers from starting further fetches.
+                                next_repo.store(repos.len(), Ordering::Relaxed);
+                                return Err(err);
+                            }
+                            Ok(()) => {
+                                debug!("Fetched repo {}", repo.name);
+                                report_progress(
+                                    options,
+                                    MergeEvent::Fetched(repo.progress.clone()),
+                                );
+                            }
+                        }
+                    }
+
+                    Ok(failures)
+                })
+            })
+            .collect();
+
+        let mut failures = HashMap::new();
+        for worker in workers {
+            failures.extend(worker.join().expect("fetch worker panicked")?);
+        }
+
+        Ok(failures)
+    })
+}
+
+/// Fetch the remote of `repo`, retrying failed fetches with exponential backoff.
+fn fetch_repo_with_retries(
+    repo: &PreparedRepo,
+    target_path: &str,
+    options: &MergeOptions,
+) -> Result<()> {
+    let mut delay = options.retry_delay;
+
+    for attempt in 1.. {
+        match fetch_repo(repo, target_path, options) {
+            Err(err) if attempt <= options.fetch_retries => {
+                warn!(
+                    "Fetch attempt {attempt} for repo {} failed, retrying in {delay:?}: {err:#}",
+                    repo.name
+                );
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            res => return res,
+        }
+    }
+
+    unreachable!("retry loop is unbounded")
+}
+
+fn fetch_repo(repo: &PreparedRepo, target_path: &str, options: &MergeOptions) -> Result<()> {
+    let mut fetch = options.git.command(target_path);
+    fetch.args(["fetch", &repo.remote]);
+    let refspec = options.fetch_refspec.as_ref().map(|refspec| {
+        refspec
+            .replace("{branch}", &repo.branch)
+            .replace("{remote}", &repo.remote)
+    });
+    let default_refspec = || format!("+refs/heads/*:refs/remotes/{}/*", repo.remote);
+    if options.preserve_tags {
+        // Tags are only fetched prefixed, so that tags of different repositories don't collide.
+        fetch.args([
+            "--no-tags".to_owned(),
+            refspec.clone().unwrap_or_else(default_refspec),
+            format!("+refs/tags/*:refs/tags/{}/*", repo.remote),
+        ]);
+    } else if let Some(refspec) = &refspec {
+        fetch.arg(refspec);
+    } else if repo.tag.is_some() {
+        // Refspecs on the command line replace the configured ones.
+        fetch.arg(default_refspec());
+    }
+    if let Some(tag) = &repo.tag {
+        // The branch created from the tag is not covered by a custom refspec, and the tag is only
+        // fetched automatically if the remote advertises it.
+        if refspec.is_some() {
+            fetch.arg(format!(
+                "+refs/heads/{branch}:refs/remotes/{}/{branch}",
+                repo.remote,
+                branch = repo.branch
+            ));
+        }
+        if !options.preserve_tags {
+            fetch.args(["tag", tag]);
+        }
+    }
+    if let Some(depth) = options.fetch_depth {
+        fetch.arg(format!("--depth={depth}"));
+    }
+    if options.jobs > 1 {
+        // Concurrent fetches would race on writing `FETCH_HEAD`.
+        fetch.arg("--no-write-fetch-head");
+    }
+
+    fetch
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("fetch for repo {} failed", repo.name))
+        .map(drop)
+}
+
+/// Remove remotes from the joined repository and temporary branches from the source repositories.
+///
+/// The merge already succeeded at this point, so failures are only reported as warnings.
+fn cleanup(git: &GitRunner, merged: &[PreparedRepo], target_path: &str) {
+    let clones_path = Path::new(target_path).join(FILTER_REPO_CLONES_PATH);
+    if clones_path.exists() {
+        if let Err(err) = fs::remove_dir_all(&clones_path) {
+            warn!(
+                "Failed to remove rewritten clones in {}: {err}",
+                clones_path.display()
+            );
+        }
+    }
+
+    for repo in merged {
+        let repo_name = &repo.name;
+        let res = git
+            .command(target_path)
+            .args(["remote", "remove", &repo.remote])
+            .output()
+            .to_anyhow();
+        if let Err(err) = res {
+            warn!("Failed to remove remote {}: {err:#}", repo.remote);
+        }
+
+        if repo.branch == TMP_JOIN_BRANCH {
+            // The temporary branch is checked out and cannot be deleted without leaving it.
+            let res = git
+                .command(&repo.path)
+                .args(["checkout", "--detach"])
+                .output()
+                .to_anyhow()
+                .and_then(|_| {
+                    git.command(&repo.path)
+                        .args(["branch", "-D", TMP_JOIN_BRANCH])
+                        .output()
+                        .to_anyhow()
+                });
+            if let Err(err) = res {
+                warn!("Failed to delete {TMP_JOIN_BRANCH} in {repo_name}: {err:#}");
+            }
+        }
+    }
+}
+
+/// Derive a valid git remote name from `repo_name` which is not contained in `taken` yet.
+///
+/// Characters not allowed in ref names are replaced with `_`.
+fn unique_remote_name(
+    repo_name: &str,
+    template: &str,
+    taken: &mut HashMap<String, String>,
+) -> String {
+    let sanitized = template
+        .replace("{repo}", repo_name)
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .map(|component| {
+            let mut component: String = component
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+                    _ => '_',
+                })
+                .collect();
+
+            // Components must not start with a dot, end with `.lock` or contain `..`.
+            if component.starts_with('.') {
+                component.replace_range(..1, "_");
+            }
+            if component.ends_with(".lock") || component.ends_with('.') {
+                component.push('_');
+            }
+            component.replace("..", "__")
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let mut remote = sanitized.clone();
+    let mut counter = 1;
+    while let Some(other_repo) = taken.get(&remote) {
+        debug!("Remote name {remote} is taken by repo {other_repo}");
+        counter += 1;
+        remote = format!("{sanitized}_{counter}");
+    }
+    taken.insert(remote.clone(), repo_name.to_owned());
+
+    remote
+}
+
+/// Path of `repo_path` relative to `root`, separated by `/` on every platform.
+///
+/// The name ends up in remote names, subdirectories and commit messages of the joined
+/// repository, where git expects forward slashes.
+fn relative_repo_name(repo_path: &Path, root: &str) -> Result<String> {
+    let rel_path = repo_path.strip_prefix(root)?;
+    let components = rel_path
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()
+        .with_context(|| format!("repository path {} is not valid UTF-8", repo_path.display()))?;
+
+    Ok(components.join("/"))
+}
+
+/// Path of the repository at `repo_path` relative to the joined repository at `target_path`.
+///
+/// Git resolves relative remote URLs from the working tree, which is where all commands on the
+/// joined repository run.
+fn relative_remote_path(repo_path: &Path, target_path: &str) -> Result<PathBuf> {
+    let repo = fs::canonicalize(repo_path)
+        .with_context(|| format!("failed to resolve repository {}", repo_path.display()))?;
+    let target = fs::canonicalize(target_path)
+        .with_context(|| format!("failed to resolve target repository {target_path}"))?;
+
+    let common = repo
+        .components()
+        .zip(target.components())
+        .take_while(|(repo, target)| repo == target)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in target.components().skip(common) {
+        relative.push("..");
+    }
+    relative.extend(repo.components().skip(common));
+
+    Ok(relative)
+}
+
+/// Subdirectory of the joined repository in which the content of repository `repo_name` is placed.
+fn target_subdir(repo_name: &str, options: &MergeOptions) -> String {
+    prefixed_subdir(&repo_subdir(repo_name, options), options)
+}
+
+/// Subdirectory of repository `repo_name` below the target prefix.
+fn repo_subdir(repo_name: &str, options: &MergeOptions) -> String {
+    match options.path_map.get(repo_name) {
+        Some(subdir) => subdir.trim_matches('/').to_owned(),
+        None => strip_components(repo_name, options.strip_prefix),
+    }
+}
+
+/// Drop the first `count` components of the `/` separated `repo_name`, keeping at least the last.
+fn strip_components(repo_name: &str, count: usize) -> String {
+    let components: Vec<_> = repo_name.split('/').collect();
+    let skip = count.min(components.len() - 1);
+
+    components[skip..].join("/")
+}
+
+/// `subdir` below the target prefix of the options, if there is one.
+fn prefixed_subdir(subdir: &str, options: &MergeOptions) -> String {
+    match options
+        .target_prefix
+        .as_deref()
+        .map(|prefix| prefix.trim_matches('/'))
+    {
+        Some(prefix) if !prefix.is_empty() => format!("{prefix}/{subdir}"),
+        _ => subdir.to_owned(),
+    }
+}
+
+/// Clone `branch` of the repository and rewrite its history to have happened in `subdir`.
+///
+/// Returns the path of the rewritten clone inside the joined repository.
+fn rewrite_history(
+    git: &GitRunner,
+    repo_path: &PathBuf,
+    branch: &str,
+    subdir: &str,
+    clone_name: &str,
+    target_path: &str,
+) -> Result<PathBuf> {
+    let clone_path = fs::canonicalize(target_path)?
+        .join(FILTER_REPO_CLONES_PATH)
+        .join(clone_name);
+    if clone_path.exists() {
+        fs::remove_dir_all(&clone_path)
+            .with_context(|| format!("failed to remove stale clone {}", clone_path.display()))?;
+    }
+
+    git.command(target_path)
+        .args([
+            "clone",
+            "--quiet",
+            "--no-local",
+            "--single-branch",
+            "--branch",
+            branch,
+        ])
+        .arg(repo_path)
+        .arg(&clone_path)
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("clone of {} failed", repo_path.display()))?;
+
+    git.command(&clone_path)
+        .args([
+            "filter-repo",
+            "--quiet",
+            "--force",
+            "--to-subdirectory-filter",
+            subdir,
+        ])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("git filter-repo in {} failed", clone_path.display()))?;
+
+    Ok(clone_path)
+}
+
+/// Maximum number of paths passed to a single git command, e.g. `git mv`.
+const MAX_PATH_BATCH_LEN: usize = 1000;
+
+/// Maximum total length in bytes of the paths passed to a single git command.
+///
+/// Well below the command line limit of Windows, the smallest one of common platforms.
+const MAX_PATH_BATCH_BYTES: usize = 16 * 1024;
+
+/// Split `paths` into batches which can each be passed to a single git command.
+fn path_batches(paths: &[OsString]) -> Vec<&[OsString]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut bytes = 0;
+
+    for (index, path) in paths.iter().enumerate() {
+        // Paths are separated by a space.
+        let len = path.len() + 1;
+        if index > start
+            && (index - start == MAX_PATH_BATCH_LEN || bytes + len > MAX_PATH_BATCH_BYTES)
+        {
+            batches.push(&paths[start..index]);
+            start = index;
+            bytes = 0;
+        }
+        bytes += len;
+    }
+    if start < paths.len() {
+        batches.push(&paths[start..]);
+    }
+
+    batches
+}
+
+/// Name for the temporary directory of a move which does not exist in the worktree.
+///
+/// Merged repository content may contain a path with the default name.
+fn unused_tmp_name(worktree_path: &str, staging_name: &str) -> String {
+    let mut tmp_name = staging_name.to_owned();
+    let mut counter = 1;
+    while Path::new(worktree_path).join(&tmp_name).exists() {
+        counter += 1;
+        tmp_name = format!("{staging_name}_{counter}");
+    }
+
+    tmp_name
+}
+
+/// File name as listed by git with `-z`, kept as raw bytes on Unix where they need not be UTF-8.
+#[cfg(unix)]
+fn git_file_name(name: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+
+    OsStr::from_bytes(name).to_os_string()
+}
+
+/// File name as listed by git with `-z`, which git for Windows lists as UTF-8.
+#[cfg(not(unix))]
+fn git_file_name(name: &[u8]) -> OsString {
+    OsString::from(String::from_utf8_lossy(name).into_owned())
+}
+
+/// Compile globs of top-level entries, e.g. of [`MergeOptions::keep_at_root`].
+fn root_patterns(patterns: &[String], kind: &str) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Pattern::new(pattern).with_context(|| format!("invalid {kind} pattern {pattern}"))
+        })
+        .collect()
+}
+
+/// Names of the top-level entries of the joined repository at `target_path`.
+fn root_entries(git: &GitRunner, target_path: &str) -> Result<HashSet<OsString>> {
+    if current_head(git, target_path).is_none() {
+        return Ok(HashSet::new());
+    }
+
+    let output = git
+        .command(target_path)
+        .args(["ls-tree", "-z", "--name-only", "HEAD"])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("failed to list top-level entries of {target_path}"))?;
+
+    Ok(output
+        .stdout
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+        .map(git_file_name)
+        .collect())
+}
+
+/// Check out `rev` in a temporary worktree of the joined repository and move its content to
+/// `subdir` there, see [`move_repo_contents`].
+///
+/// The worktree only holds the content of this repository, so nothing merged before is moved
+/// along, even for repositories sharing a parent directory like `a/b` and `a/c`.
+///
+/// Returns the move commit, whose tree only contains `subdir` and the entries kept at the root.
+fn move_in_worktree(
+    git: &GitRunner,
+    rev: &str,
+    repo_name: &str,
+    subdir: &str,
+    trailer: &str,
+    target_path: &str,
+    options: &MergeOptions,
+) -> Result<String> {
+    let worktree = fs::canonicalize(target_path)?.join(MOVE_WORKTREE_PATH);
+    let worktree_path = worktree
+        .to_str()
+        .with_context(|| format!("worktree path {} is not valid UTF-8", worktree.display()))?;
+
+    // Kept entries must not collide with the root of the joined repository when merging.
+    let taken = if options.keep_at_root.is_empty() {
+        HashSet::new()
+    } else {
+        root_entries(git, target_path)?
+    };
+
+    // A worktree left behind by an interrupted merge is in the way.
+    remove_worktree(git, target_path, worktree_path);
+    git.command(target_path)
+        .args(["worktree", "add", "--detach", worktree_path, rev])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("checkout of {rev} in a temporary worktree failed"))?;
+
+    let moved = move_repo_contents(repo_name, subdir, trailer, worktree_path, &taken, options)
+        .and_then(|_| {
+            git.command(worktree_path)
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .to_anyhow()
+                .with_context(|| format!("failed to resolve move commit of repo {repo_name}"))
+        });
+    remove_worktree(git, target_path, worktree_path);
+
+    Ok(String::from_utf8_lossy(&moved?.stdout).trim().to_owned())
+}
+
+/// Remove the temporary worktree at `worktree_path` if there is one.
+fn remove_worktree(git: &GitRunner, target_path: &str, worktree_path: &str) {
+    if !Path::new(worktree_path).exists() {
+        return;
+    }
+
+    let res = git
+        .command(target_path)
+        .args(["worktree", "remove", "--force", worktree_path])
+        .output()
+        .to_anyhow();
+    if let Err(err) = res {
+        warn!("Failed to remove temporary worktree {worktree_path}: {err:#}");
+    }
+}
+
+/// Move all content of the worktree at `worktree_path` to `subdir` in a commit ending with
+/// `trailer`.
+///
+/// Top-level entries matching [`MergeOptions::keep_at_root`] stay in place unless their name is
+/// in `taken`.
+fn move_repo_contents(
+    repo_name: &str,
+    subdir: &str,
+    trailer: &str,
+    worktree_path: &str,
+    taken: &HashSet<OsString>,
+    options: &MergeOptions,
+) -> Result<()> {
+    let git = &options.git;
+    let keep_at_root = root_patterns(&options.keep_at_root, "keep at root")?;
+
+    // Some repositories contain a folder with their own name, e.g. `googletest/googletest`.
+    // To be able to handle them, we move repository content first to a temporary path
+    // and then to the permanent location.
+    let tmp_name = unused_tmp_name(worktree_path, &options.staging_name);
+    let tmp_repo_target_path = Path::new(worktree_path).join(&tmp_name);
+    let repo_target_path = Path::new(worktree_path).join(subdir);
+
+    // Only tracked content is moved, anything else in the worktree like its `.git` file, the
+    // temporary path or ignored files stays in place.
+    let output = git
+        .command(worktree_path)
+        .args(["ls-tree", "-z", "--name-only", "HEAD"])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("failed to list top-level entries of repo {repo_name}"))?;
+    let mut top_level_files = Vec::new();
+    for name in output.stdout.split(|&byte| byte == 0) {
+        if name.is_empty() {
+            continue;
+        }
+        let name = git_file_name(name);
+        let kept = keep_at_root
+            .iter()
+            .any(|pattern| pattern.matches(&name.to_string_lossy()));
+        if kept && !taken.contains(&name) {
+            debug!(
+                "Keeping {} of {repo_name} at the root",
+                name.to_string_lossy()
+            );
+            continue;
+        }
+        if kept {
+            warn!(
+                "Moving {} of {repo_name} to {subdir}, the joined repository has it at the root \
+                 already",
+                name.to_string_lossy()
+            );
+        }
+        top_level_files.push(name);
+    }
+    top_level_files.sort();
+
+    debug!("Files to move to {repo_name}:");
+    for file_ in top_level_files.iter() {
+        debug!("  {}", file_.to_string_lossy());
+    }
+
+    let commit_move = || {
+        let mut move_commit = git.command(worktree_path);
+        move_commit.args([
+            "commit",
+            "-m",
+            &options.move_message_template.replace("{repo}", repo_name),
+            "-m",
+            trailer,
+        ]);
+        if top_level_files.is_empty() {
+            // Branches with an empty tree still get a move commit recording their source.
+            move_commit.arg("--allow-empty");
+        }
+        move_commit
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("move commit for repo {repo_name} failed"))
+            .map(drop)
+    };
+
+    // Without amending, content can be moved directly unless it has an entry with the name of
+    // the first component of the subdirectory.
+    let direct = options.no_amend
+        && !top_level_files
+            .iter()
+            .any(|name| Path::new(subdir).starts_with(name));
+    let move_target = if direct {
+        fs::create_dir_all(&repo_target_path)
+            .with_context(|| "failed to create repo target path")?;
+        format!("{subdir}/")
+    } else {
+        debug!("Staging content of {repo_name} in {tmp_name}");
+        fs::create_dir_all(&tmp_repo_target_path)
+            .with_context(|| "failed to create temporary repo target path")?;
+        format!("{tmp_name}/")
+    };
+
+    // Move all merged repository content to its subdirectory or the temporary path.
+    // Joined repositories can have lots of top-level entries, so they are moved in batches to
+    // stay below command line length limits.
+    for batch in path_batches(&top_level_files) {
+        git.command(worktree_path)
+            .arg("mv")
+            .args(batch)
+            .arg(&move_target)
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("move to {move_target} for repo {repo_name} failed"))?;
+    }
+
+    if top_level_files.is_empty() {
+        if !direct {
+            let _ = fs::remove_dir(&tmp_repo_target_path);
+        }
+        return commit_move();
+    }
+
+    if !direct {
+        if !options.no_amend {
+            commit_move()?;
+        }
+
+        // Move all merged repository content to final location.
+        // We do this only after merging the content to a temporary path because some content
+        // may have the same name as the final location.
+        if subdir.contains('/') {
+            // Create parent before moving
+            let parent = repo_target_path
+                .parent()
+                .with_context(|| "failed to find parent of repo target path")?;
+            fs::create_dir_all(parent)?;
+        }
+
+        git.command(worktree_path)
+            .args(["mv", &tmp_name, subdir])
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("move to {subdir} for repo {repo_name} failed"))?;
+    }
+
+    normalize_eol(git, worktree_path, subdir, options.normalize_eol)
+        .with_context(|| format!("normalizing line endings of repo {repo_name} failed"))?;
+
+    if options.no_amend {
+        commit_move()
+    } else {
+        git.command(worktree_path)
+            .args(["commit", "--amend", "--no-edit"])
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("amending move commit for repo {repo_name} failed"))
+            .map(drop)
+    }
+}
+
+/// Convert the line endings of the text files in `subdir` of the worktree to `mode` and stage them.
+fn normalize_eol(git: &GitRunner, worktree_path: &str, subdir: &str, mode: EolMode) -> Result<()> {
+    if mode == EolMode::None {
+        return Ok(());
+    }
+
+    // Git detects text files and their line endings in the index the same way as for
+    // `text=auto`.
+    let output = git
+        .command(worktree_path)
+        .args(["ls-files", "--eol", "-z", "--", subdir])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("listing line endings in {subdir} failed"))?;
+
+    let mut paths = Vec::new();
+    for entry in output.stdout.split(|&byte| byte == 0) {
+        let Ok(entry) = std::str::from_utf8(entry) else {
+            continue;
+        };
+        let Some((info, path)) = entry.split_once('\t') else {
+            continue;
+        };
+        let index_eol = info
+            .split_whitespace()
+            .next()
+            .and_then(|field| field.strip_prefix("i/"))
+            .unwrap_or_default();
+        let attr = info.split_once("attr/").map_or("", |(_, attr)| attr.trim());
+        if !needs_eol_conversion(mode, index_eol, attr) {
+            continue;
+        }
+
+        let file_path = Path::new(worktree_path).join(path);
+        if file_path.is_symlink() {
+            continue;
+        }
+        let content = fs::read(&file_path)
+            .with_context(|| format!("failed to read {}", file_path.display()))?;
+        fs::write(&file_path, convert_eol(&content, mode))
+            .with_context(|| format!("failed to write {}", file_path.display()))?;
+        paths.push(OsString::from(path));
+    }
+    debug!(
+        "Converted line endings of {} files in {subdir}",
+        paths.len()
+    );
+
+    // The converted content has to be staged as is, without conversions configured by the user.
+    for batch in path_batches(&paths) {
+        git.command(worktree_path)
+            .config("core.autocrlf", "false")
+            .config("core.safecrlf", "false")
+            .args(["add", "--"])
+            .args(batch)
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("staging converted files in {subdir} failed"))?;
+    }
+
+    Ok(())
+}
+
+/// Whether a file with the line endings `index_eol` and the attributes `attr`, as reported by
+/// `git ls-files --eol`, has to be converted to `mode`.
+fn needs_eol_conversion(mode: EolMode, index_eol: &str, attr: &str) -> bool {
+    // Binary files, files without line breaks and files excluded from conversion are kept.
+    if matches!(index_eol, "" | "none" | "-text") || attr.contains("-text") {
+        return false;
+    }
+
+    match mode {
+        EolMode::None => false,
+        EolMode::Lf => index_eol != "lf",
+        // Git would normalize files with text attributes back to LF.
+        EolMode::Crlf => index_eol != "crlf" && attr.is_empty(),
+    }
+}
+
+/// Convert all line endings of `content` to `mode`.
+fn convert_eol(content: &[u8], mode: EolMode) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(content.len());
+    for (idx, &byte) in content.iter().enumerate() {
+        let after_cr = idx > 0 && content[idx - 1] == b'\r';
+        match (mode, byte) {
+            (EolMode::Lf, b'\r') if content.get(idx + 1) == Some(&b'\n') => {}
+            (EolMode::Crlf, b'\n') if !after_cr => converted.extend_from_slice(b"\r\n"),
+            _ => converted.push(byte),
+        }
+    }
+
+    converted
+}
+
+/// Check that `subdir` of the joined repository contains the tree of `remote_branch`.
+fn verify_subdir(
+    git: &GitRunner,
+    remote_branch: &str,
+    subdir: &str,
+    target_path: &str,
+    options: &MergeOptions,
+) -> Result<()> {
+    // Rewritten histories contain the subdirectory already.
+    let expected_rev = if options.strategy == MergeStrategy::FilterRepo && !options.squash_history {
+        format!("{remote_branch}:{subdir}")
+    } else {
+        format!("{remote_branch}^{{tree}}")
+    };
+    let resolve_tree = |rev: &str| -> Result<String> {
+        let output = git
+            .command(target_path)
+            .args(["rev-parse", "--verify", rev])
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("failed to resolve tree {rev}"))?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    };
+    let expected = resolve_tree(&expected_rev)?;
+    // Git does not track empty directories, so an empty tree leaves no subdirectory.
+    let actual = match resolve_tree(&format!("HEAD:{subdir}")) {
+        Err(_) if is_empty_tree(git, &expected, target_path)? => return Ok(()),
+        actual => actual?,
+    };
+    if expected == actual {
+        debug!("Verified content of {subdir} against {remote_branch}");
+        return Ok(());
+    }
+
+    let output = git
+        .command(target_path)
+        .args(["diff-tree", "-r", "--name-only", &expected, &actual])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("failed to compare {subdir} with {remote_branch}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let paths: Vec<_> = stdout.lines().collect();
+    let mut message = format!(
+        "{subdir} differs from {remote_branch} in {} paths: {}",
+        paths.len(),
+        paths[..paths.len().min(MAX_REPORTED_CONFLICTS)].join(", ")
+    );
+    if paths.len() > MAX_REPORTED_CONFLICTS {
+        message.push_str(", ...");
+    }
+
+    bail!(message)
+}
+
+/// Whether `tree` has no entries.
+fn is_empty_tree(git: &GitRunner, tree: &str, target_path: &str) -> Result<bool> {
+    let output = git
+        .command(target_path)
+        .args(["ls-tree", tree])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("failed to list tree {tree}"))?;
+
+    Ok(output.stdout.is_empty())
+}
+
+/// Report for a repository which failed with `err` and was skipped.
+fn failed_report(repo_path: &Path, branch: &str, subdir: &str, err: &anyhow::Error) -> RepoReport {
+    RepoReport {
+        source: repo_path.to_owned(),
+        branch: branch.to_owned(),
+        subdir: subdir.to_owned(),
+        commit: None,
+        status: RepoStatus::Failed,
+        error: Some(format!("{err:#}")),
+        verified: false,
+    }
+}
+
+/// Commit checked out in the joined repository, if there is any.
+fn current_head(git: &GitRunner, target_path: &str) -> Option<String> {
+    git.command(target_path)
+        .args(["rev-parse", "--verify", "--quiet", "HEAD"])
+        .output()
+        .to_anyhow()
+        .ok()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Undo a failed merge of a repository by resetting the joined repository to `head`.
+///
+/// Without `head`, the joined repository had no commits before, so all commits are dropped.
+fn rollback_merge(git: &GitRunner, target_path: &str, head: Option<&str>) {
+    // Fails if no merge is in progress, which is fine.
+    let _ = git.command(target_path).args(["merge", "--abort"]).output();
+
+    let res = match head {
+        Some(head) => git
+            .command(target_path)
+            .args(["reset", "--hard", head])
+            .output()
+            .to_anyhow(),
+        None => git
+            .command(target_path)
+            .args(["update-ref", "-d", "HEAD"])
+            .output()
+            .to_anyhow()
+            .and_then(|_| {
+                git.command(target_path)
+                    .args(["read-tree", "--empty"])
+                    .output()
+                    .to_anyhow()
+            }),
+    }
+    .and_then(|_| {
+        git.command(target_path)
+            .args(["clean", "-fd"])
+            .output()
+            .to_anyhow()
+    });
+
+    match res {
+        Ok(_) => info!(
+            "Rolled back the joined repository {target_path} to {}",
+            head.unwrap_or("no commits")
+        ),
+        Err(err) => warn!("Failed to roll back the joined repository {target_path}: {err:#}"),
+    }
+}
+
+/// Handle the submodules of `repo` in its subdirectory of the joined repository according to `mode`.
+fn handle_submodules(
+    git: &GitRunner,
+    repo: &PreparedRepo,
+    subdir: &str,
+    target_path: &str,
+    mode: SubmoduleMode,
+) -> Result<()> {
+    let output = git
+        .command(target_path)
+        .args(["ls-files", "--stage", "--", subdir])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("listing files in {subdir} failed"))?;
+    // Lines have the form `<mode> <object> <stage>\t<path>`, gitlinks have mode 160000.
+    let gitlinks: Vec<(String, String)> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (info, path) = line.split_once('\t')?;
+            let mut info = info.split_whitespace();
+            let (mode, object) = (info.next()?, info.next()?);
+            (mode == "160000").then(|| (path.to_owned(), object.to_owned()))
+        })
+        .collect();
+    let gitmodules = format!("{subdir}/.gitmodules");
+    let has_gitmodules = Path::new(target_path).join(&gitmodules).is_file();
+
+    if gitlinks.is_empty() && !has_gitmodules {
+        return Ok(());
+    }
+
+    let repo_name = &repo.name;
+    if mode == SubmoduleMode::Keep {
+        warn!(
+            "Repo {repo_name} contains {} submodules, they won't resolve in {subdir} of the joined \
+             repository since git only reads .gitmodules at the root",
+            gitlinks.len()
+        );
+        return Ok(());
+    }
+
+    if !gitlinks.is_empty() {
+        let paths: Vec<_> = gitlinks
+            .iter()
+            .map(|(path, _)| OsString::from(path))
+            .collect();
+        for batch in path_batches(&paths) {
+            git.command(target_path)
+                .args(["rm", "--cached", "--quiet", "--"])
+                .args(batch)
+                .output()
+                .to_anyhow()
+                .with_context(|| "removing gitlinks failed")?;
+        }
+        for (path, _) in gitlinks.iter() {
+            // Gitlinks are checked out as empty directories.
+            let _ = fs::remove_dir(Path::new(target_path).join(path));
+        }
+    }
+    if has_gitmodules {
+        git.command(target_path)
+            .args(["rm", "--quiet", &gitmodules])
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("removing {gitmodules} failed"))?;
+    }
+
+    if mode == SubmoduleMode::Inline {
+        for (path, commit) in gitlinks.iter() {
+            let rel_path = path.strip_prefix(&format!("{subdir}/")).unwrap_or(path);
+            let submodule_path = repo.path.join(rel_path);
+            if let Err(err) = inline_submodule(git, &submodule_path, path, commit, target_path) {
+                warn!(
+                    "Removed submodule {rel_path} of repo {repo_name} without inlining it: {err:#}"
+                );
+            }
+        }
+    }
+
+    let message = match mode {
+        SubmoduleMode::Inline => format!("Inline submodules of {repo_name}"),
+        _ => format!("Remove submodules of {repo_name}"),
+    };
+    git.command(target_path)
+        .args(["commit", "-m", &message])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("committing submodule changes of {repo_name} failed"))
+        .map(drop)
+}
+
+/// Add the content of `commit` from the submodule checkout at `submodule_path` at `path` of the
+/// joined repository.
+fn inline_submodule(
+    git: &GitRunner,
+    submodule_path: &Path,
+    path: &str,
+    commit: &str,
+    target_path: &str,
+) -> Result<()> {
+    if !submodule_path.join(".git").exists() {
+        bail!(
+            "submodule is not checked out at {}",
+            submodule_path.display()
+        );
+    }
+
+    git.command(target_path)
+        .args(["fetch", "--quiet", "--no-tags"])
+        .arg(submodule_path)
+        .arg(commit)
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("fetching {commit} from {} failed", submodule_path.display()))?;
+    git.command(target_path)
+        .args(["read-tree", &format!("--prefix={path}/"), "-u", commit])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("reading {commit} into {path} failed"))?;
+
+    if Path::new(target_path)
+        .join(path)
+        .join(".gitmodules")
+        .is_file()
+    {
+        warn!("Inlined submodule {path} has submodules itself, they are kept as they are");
+    }
+
+    Ok(())
+}
+
+/// Check whether the top-level `.gitattributes` on `branch` of a repository stores files in LFS.
+fn uses_lfs(git: &GitRunner, repo_path: &PathBuf, branch: &str) -> bool {
+    git.command(repo_path)
+        .args(["show", &format!("{branch}:.gitattributes")])
+        .output()
+        .to_anyhow()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains("filter=lfs"))
+}
+
+/// Fetch the LFS objects of `branch` in a source repository from origin and copy them to the joined
+/// repository.
+///
+/// Both repositories store LFS objects by their hash in `lfs/objects` of their git directory, so
+/// copying them is enough for `git lfs checkout` to find them.
+fn import_lfs_objects(
+    git: &GitRunner,
+    repo_path: &PathBuf,
+    branch: &str,
+    target_path: &str,
+) -> Result<()> {
+    // Objects may be available locally already, e.g. in repositories without remotes.
+    if let Err(err) = git
+        .command(repo_path)
+        .args(["lfs", "fetch", "origin", branch])
+        .output()
+        .to_anyhow()
+    {
+        warn!(
+            "git lfs fetch in {} failed, only copying local LFS objects: {err:#}",
+            repo_path.display()
+        );
+    }
+
+    let git_dir = if is_bare_repo(git, repo_path)? {
+        repo_path.clone()
+    } else {
+        repo_path.join(".git")
+    };
+    let source_objects = git_dir.join("lfs").join("objects");
+    if !source_objects.is_dir() {
+        debug!("No LFS objects in {}", repo_path.display());
+        return Ok(());
+    }
+
+    copy_dir(
+        &source_objects,
+        &Path::new(target_path)
+            .join(".git")
+            .join("lfs")
+            .join("objects"),
+    )
+}
+
+/// Recursively copy the files of `from` to `to`, keeping files which exist already.
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to).with_context(|| format!("failed to create {}", to.display()))?;
+
+    for entry in fs::read_dir(from).with_context(|| format!("failed to read {}", from.display()))? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else if !target.exists() {
+            fs::copy(entry.path(), &target)
+                .with_context(|| format!("failed to copy {}", entry.path().display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Install the LFS filters in the joined repository and replace pointer files with their content.
+fn checkout_lfs_objects(git: &GitRunner, target_path: &str) -> Result<()> {
+    git.command(target_path)
+        .args(["lfs", "install", "--local"])
+        .output()
+        .to_anyhow()
+        .with_context(|| "git lfs install failed")?;
+    git.command(target_path)
+        .args(["lfs", "checkout"])
+        .output()
+        .to_anyhow()
+        .with_context(|| "git lfs checkout failed")
+        .map(drop)
+}
+
+/// Move top-level files of `subdirs` matching `patterns` to the root of the joined repository if
+/// all their copies are identical, and commit it.
+///
+/// A file is only moved if it is in at least two subdirectories and the root has no different
+/// entry of that name. Nothing is committed if no file is moved.
+fn dedup_root_files(
+    git: &GitRunner,
+    patterns: &[String],
+    subdirs: &[&str],
+    target_path: &str,
+) -> Result<()> {
+    let patterns = root_patterns(patterns, "dedup root files")?;
+
+    // Mode and object of every tree entry, so that identical files have the same entry.
+    let list_entries = |path: &str| -> Result<Vec<(String, String)>> {
+        let output = git
+            .command(target_path)
+            .args(["ls-tree", "-z", "HEAD", "--", path])
+            .output()
+            .to_anyhow()
+            .with_context(|| format!("listing {path} of the joined repository failed"))?;
+        // Files with names which are not UTF-8 are left alone instead of being mangled.
+        Ok(output
+            .stdout
+            .split(|&byte| byte == 0)
+            .filter_map(|entry| {
+                let (info, path) = std::str::from_utf8(entry).ok()?.split_once('\t')?;
+                let (mode, rest) = info.split_once(' ')?;
+                let (kind, object) = rest.split_once(' ')?;
+                (kind == "blob").then(|| (path.to_owned(), format!("{mode} {object}")))
+            })
+            .collect())
+    };
+
+    let mut copies: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    for subdir in subdirs {
+        for (path, entry) in list_entries(&format!("{subdir}/"))? {
+            let name = path.rsplit('/').next().unwrap_or(&path).to_owned();
+            if patterns.iter().any(|pattern| pattern.matches(&name)) {
+                copies.entry(name).or_default().push((path, entry));
+            }
+        }
+    }
+    let mut names: Vec<_> = copies.keys().cloned().collect();
+    names.sort();
+
+    let mut moved = 0;
+    for name in names {
+        let copies = &copies[&name];
+        if copies.len() < 2 {
+            continue;
+        }
+        if copies.iter().any(|(_, entry)| *entry != copies[0].1) {
+            warn!(
+                "Keeping {name} in {} subdirectories, their content differs",
+                copies.len()
+            );
+            continue;
+        }
+        let at_root = Path::new(target_path).join(&name);
+        let root_entry = list_entries(&name)?.into_iter().next();
+        if root_entry
+            .as_ref()
+            .is_some_and(|(_, entry)| *entry != copies[0].1)
+            || (root_entry.is_none() && fs::symlink_metadata(&at_root).is_ok())
+        {
+            warn!(
+                "Keeping {name} in {} subdirectories, the root has a different {name}",
+                copies.len()
+            );
+            continue;
+        }
+
+        let mut duplicates: Vec<OsString> = copies.iter().map(|(path, _)| path.into()).collect();
+        if root_entry.is_none() {
+            let (first, _) = &copies[0];
+            git.command(target_path)
+                .args(["mv", "--", first, &name])
+                .output()
+                .to_anyhow()
+                .with_context(|| format!("moving {first} to the root failed"))?;
+            duplicates.remove(0);
+        }
+        for batch in path_batches(&duplicates) {
+            git.command(target_path)
+                .args(["rm", "--quiet", "--"])
+                .args(batch)
+                .output()
+                .to_anyhow()
+                .with_context(|| format!("removing copies of {name} failed"))?;
+        }
+        debug!(
+            "Moved {name} of {} subdirectories to the root",
+            copies.len()
+        );
+        moved += 1;
+    }
+
+    if moved == 0 {
+        debug!("No identical files to move to the root");
+        return Ok(());
+    }
+    info!("Moved {moved} identical files to the root");
+
+    git.command(target_path)
+        .args(["commit", "-m", DEDUP_COMMIT_MESSAGE])
+        .output()
+        .to_anyhow()
+        .with_context(|| "committing identical files moved to the root failed")
+        .map(drop)
+}
+
+/// Remove placeholder files of directories without other tracked content and directories left
+/// empty in the working tree of the joined repository.
+///
+/// Placeholders are removed with `git rm` and committed, untracked files are never touched.
+fn prune_empty_dirs(git: &GitRunner, target_path: &str) -> Result<()> {
+    let output = git
+        .command(target_path)
+        .args(["ls-files", "-z"])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("listing tracked files of {target_path} failed"))?;
+    let tracked = String::from_utf8_lossy(&output.stdout);
+    let tracked: Vec<_> = tracked
+        .split('\0')
+        .filter(|path| !path.is_empty())
+        .collect();
+
+    let is_placeholder = |path: &str| {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        PLACEHOLDER_FILES.contains(&name)
+    };
+
+    // Directories with tracked files other than placeholders somewhere below them.
+    let mut content_dirs = HashSet::new();
+    for path in tracked.iter().filter(|path| !is_placeholder(path)) {
+        let mut dir = *path;
+        while let Some((parent, _)) = dir.rsplit_once('/') {
+            if !content_dirs.insert(parent) {
+                // The parents of a known directory are known as well.
+                break;
+            }
+            dir = parent;
+        }
+    }
+
+    let placeholders: Vec<OsString> = tracked
+        .iter()
+        .filter(|path| {
+            is_placeholder(path)
+                && path
+                    .rsplit_once('/')
+                    .is_some_and(|(parent, _)| !content_dirs.contains(parent))
+        })
+        .map(OsString::from)
+        .collect();
+
+    // Removing the placeholders also removes the directories they leave empty.
+    for batch in path_batches(&placeholders) {
+        git.command(target_path)
+            .args(["rm", "--quiet", "--"])
+            .args(batch)
+            .output()
+            .to_anyhow()
+            .with_context(|| "removing placeholder files failed")?;
+    }
+    remove_empty_dirs(Path::new(target_path))?;
+
+    if placeholders.is_empty() {
+        debug!("No placeholders of empty directories to remove");
+        return Ok(());
+    }
+    info!(
+        "Removed {} placeholders of empty directories",
+        placeholders.len()
+    );
+
+    git.command(target_path)
+        .args(["commit", "-m", PRUNE_COMMIT_MESSAGE])
+        .output()
+        .to_anyhow()
+        .with_context(|| "committing removed placeholders failed")
+        .map(drop)
+}
+
+/// Remove empty directories below `dir` bottom-up and return whether `dir` is empty afterwards.
+///
+/// Symbolic links and `.git` are left alone and count as content.
+fn remove_empty_dirs(dir: &Path) -> Result<bool> {
+    let mut empty = true;
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))? {
+        let entry = entry.with_context(|| format!("failed to read entry of {}", dir.display()))?;
+        let path = entry.path();
+
+        if entry.file_name() != ".git" && entry.file_type()?.is_dir() && remove_empty_dirs(&path)? {
+            fs::remove_dir(&path)
+                .with_context(|| format!("failed to remove empty directory {}", path.display()))?;
+            debug!("Removed empty directory {}", path.display());
+        } else {
+            empty = false;
+        }
+    }
+
+    Ok(empty)
+}
+
+/// Append the lines of the `file_name` files in `subdirs` to the root `file_name` and commit it.
+///
+/// Every line is translated by `translate` with its subdirectory. The files in the subdirectories
+/// stay in place. Lines already in the root file are not added again, so nothing is committed if
+/// there are no new lines.
+fn combine_root_file(
+    git: &GitRunner,
+    file_name: &str,
+    subdirs: &[&str],
+    target_path: &str,
+    translate: impl Fn(&str, &str) -> Option<String>,
+    message: &str,
+) -> Result<()> {
+    let root_file = Path::new(target_path).join(file_name);
+    let mut content = match fs::read_to_string(&root_file) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", root_file.display()))
+        }
+    };
+    let mut patterns: HashSet<String> = content.lines().map(|line| line.to_owned()).collect();
+
+    let mut added = Vec::new();
+    for subdir in subdirs {
+        let path = Path::new(target_path).join(subdir).join(file_name);
+        let Ok(subdir_content) = fs::read_to_string(&path) else {
+            continue;
+        };
+
+        for line in subdir_content.lines() {
+            if let Some(pattern) = translate(line, subdir) {
+                if patterns.insert(pattern.clone()) {
+                    added.push(pattern);
+                }
+            }
+        }
+    }
+
+    if added.is_empty() {
+        debug!("No {file_name} lines to add to the root of the joined repository");
+        return Ok(());
+    }
+    debug!("Adding {} lines to the root {file_name}", added.len());
+
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    for pattern in added {
+        content.push_str(&pattern);
+        content.push('\n');
+    }
+    fs::write(&root_file, content)
+        .with_context(|| format!("failed to write {}", root_file.display()))?;
+
+    git.command(target_path)
+        .args(["add", file_name])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("adding {file_name} failed"))?;
+    git.command(target_path)
+        .args(["commit", "-m", message])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("committing {file_name} failed"))
+        .map(drop)
+}
+
+/// Translate a line of the `.gitignore` in `subdir` to a pattern for the root `.gitignore`.
+///
+/// Patterns without a slash (apart from a trailing one) match at any depth and are kept as they
+/// are, others are relative to `subdir` and get prefixed with it. Comments and empty lines are
+/// dropped.
+fn root_gitignore_pattern(line: &str, subdir: &str) -> Option<String> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let (negation, pattern) = match line.strip_prefix('!') {
+        Some(pattern) => ("!", pattern),
+        None => ("", line),
+    };
+    if pattern.trim_end_matches('/').contains('/') {
+        Some(format!(
+            "{negation}/{subdir}/{}",
+            pattern.trim_start_matches('/')
+        ))
+    } else {
+        Some(line.to_owned())
+    }
+}
+
+/// Translate a line of the `.gitattributes` in `subdir` to a line for the root `.gitattributes`.
+///
+/// Patterns are relative to `subdir`, so they get prefixed with it. Patterns without a slash match
+/// at any depth below `subdir`, e.g. `*.bin` becomes `src/foo/**/*.bin`. Macro definitions are only
+/// honored at the root and are kept as they are. Comments and empty lines are dropped.
+fn root_gitattributes_line(line: &str, subdir: &str) -> Option<String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    if line.starts_with("[attr]") {
+        return Some(line.to_owned());
+    }
+
+    // Quoted patterns may contain whitespace.
+    let (quote, unquoted) = match line.strip_prefix('"') {
+        Some(unquoted) => ("\"", unquoted),
+        None => ("", line),
+    };
+    let pattern_end = if quote.is_empty() {
+        unquoted.find(char::is_whitespace)
+    } else {
+        unquoted.find('"')
+    }
+    .unwrap_or(unquoted.len());
+    let (pattern, attributes) = unquoted.split_at(pattern_end);
+
+    let pattern = if pattern.contains('/') {
+        format!("{subdir}/{}", pattern.trim_start_matches('/'))
+    } else {
+        format!("{subdir}/**/{pattern}")
+    };
+
+    Some(format!("{quote}{pattern}{attributes}"))
+}
+
+/// Reference pointed to by the manifest in a source repository.
+enum ManifestRef {
+    /// Name of a regular branch, without the remote prefix.
+    Branch(String),
+    /// Name of a tag.
+    Tag(String),
+}
+
+/// Name of the branch created in a source repository when the manifest points to a tag.
+const TMP_JOIN_BRANCH: &str = "tmp_join_branch";
+
+/// Name of the branch holding the split history in the joined repository while it is cloned.
+const TMP_SPLIT_BRANCH: &str = "trenza-split";
+
+/// Find the reference pointed to by the manifest, falling back to the default branch if requested.
+fn find_manifest_ref(
+    git: &GitRunner,
+    repo_path: &PathBuf,
+    re: &mut Regex,
+    fallback_default_branch: bool,
+) -> Result<ManifestRef> {
+    // Retrieve remote branches in the source repository.
+    let output = git
+        .command(repo_path)
+        .args(["branch", "-r"])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("listing remote branches in {} failed", repo_path.display()))?;
+    let remote_branches = String::from_utf8_lossy(&output.stdout);
+
+    // Find the branch/tag pointed to by the manifest.
+    let mut candidates: Vec<&str> = Vec::new();
+    for caps in re.captures_iter(&remote_branches) {
+        let candidate = caps.get(1).map_or("", |m| m.as_str());
+        if !candidates.contains(&candidate) {
+            candidates.push(candidate);
+        }
+    }
+    debug!(
+        "Manifest candidates in {}: {candidates:?}",
+        repo_path.display()
+    );
+    if candidates.len() > 1 {
+        bail!(
+            "manifest remote branches in {} point to several refs ({}), pass --branch to \
+             choose one",
+            repo_path.display(),
+            candidates.join(", ")
+        );
+    }
+
+    if let Some(&manifest_branch) = candidates.first() {
+        if manifest_branch.contains('/') {
+            let manifest_branch = manifest_branch
+                .split('/')
+                .next_back()
+                .map(ToOwned::to_owned)
+                .with_context(|| "failed to identify manifest branch")?;
+
+            return Ok(ManifestRef::Branch(manifest_branch));
+        } else {
+            return Ok(ManifestRef::Tag(manifest_branch.to_owned()));
+        }
+    }
+
+    if fallback_default_branch {
+        let default_branch = detect_default_branch(git, repo_path)?;
+        info!(
+            "No manifest branch found in {}, using default branch {default_branch}",
+            repo_path.display()
+        );
+
+        return Ok(ManifestRef::Branch(default_branch));
+    }
+
+    bail!("failed to find manifest branch in {}", repo_path.display())
+}
+
+/// Find the default branch of the `origin` remote, i.e. the branch `origin/HEAD` points to.
+fn detect_default_branch(git: &GitRunner, repo_path: &PathBuf) -> Result<String> {
+    let output = git
+        .command(repo_path)
+        .args(["symbolic-ref", "--short", "refs/remotes/origin/HEAD"])
+        .output()
+        .to_anyhow()
+        .with_context(|| {
+            format!(
+                "failed to find default branch of origin in {}; \
+                 try `git remote set-head origin --auto` or pass a branch",
+                repo_path.display()
+            )
+        })?;
+    let origin_head = String::from_utf8_lossy(&output.stdout);
+
+    origin_head
+        .trim()
+        .strip_prefix("origin/")
+        .map(ToOwned::to_owned)
+        .with_context(|| format!("unexpected default branch {origin_head:?} of origin"))
+}
+
+/// Check out the branch or tag the manifest points to and return the branch to merge with the tag,
+/// if any.
+fn prepare_manifest_branch(
+    git: &GitRunner,
+    repo_path: &PathBuf,
+    re: &mut Regex,
+    fallback_default_branch: bool,
+) -> Result<(String, Option<String>)> {
+    match find_manifest_ref(git, repo_path, re, fallback_default_branch)? {
+        ManifestRef::Branch(manifest_branch) => {
+            // Regular branch - check it out to have it available for the merge.
+            git.command(repo_path)
+                .args(["checkout", &manifest_branch])
+                .output()
+                .to_anyhow()
+                .with_context(|| format!("checkout of manifest branch {manifest_branch} failed"))?;
+
+            Ok((manifest_branch, None))
+        }
+        ManifestRef::Tag(manifest_tag) => {
+            // The manifest points to a tag - check it out to a temporary branch name. A branch
+            // left behind by an earlier merge is reset so that it cannot point to a stale commit.
+            git.command(repo_path)
+                .args(["checkout", "-B", TMP_JOIN_BRANCH, &manifest_tag])
+                .output()
+                .to_anyhow()
+                .with_context(|| format!("checkout of manifest tag {manifest_tag} failed"))?;
+
+            Ok((TMP_JOIN_BRANCH.to_owned(), Some(manifest_tag)))
+        }
+    }
+}
+
+fn resolve_manifest_branch(
+    git: &GitRunner,
+    repo_path: &PathBuf,
+    re: &mut Regex,
+    fallback_default_branch: bool,
+) -> Result<(String, Option<String>)> {
+    match find_manifest_ref(git, repo_path, re, fallback_default_branch)? {
+        // Branches listed by the manifest are remote branches and thus always available.
+        ManifestRef::Branch(manifest_branch) => Ok((manifest_branch, None)),
+        ManifestRef::Tag(manifest_tag) => {
+            verify_ref(git, repo_path, &manifest_tag)?;
+            Ok((
+                format!("{TMP_JOIN_BRANCH} (tag {manifest_tag})"),
+                Some(manifest_tag),
+            ))
+        }
+    }
+}
+
+/// Check out `branch`, or the first of `fallbacks` which exists if it does not.
+///
+/// Returns the branch which was checked out.
+fn prepare_requested_branch(
+    git: &GitRunner,
+    repo_path: &PathBuf,
+    branch: &str,
+    fallbacks: &[String],
+) -> Result<String> {
+    if is_bare_repo(git, repo_path)? {
+        // Branches of bare repositories are available for the merge without checkout.
+        return resolve_requested_branch(git, repo_path, branch, fallbacks);
+    }
+    let branch = existing_fallback(git, repo_path, branch, fallbacks).unwrap_or(branch);
+
+    let checkout = git
+        .command(repo_path)
+        .args(["checkout", branch])
+        .output()
+        .to_anyhow();
+    if let Err(err) = checkout {
+        if !ref_exists(git, repo_path, branch) {
+            return Err(missing_branch_error(git, repo_path, branch));
+        }
+        return Err(err.context(format!("checkout of branch {branch} failed")));
+    }
+
+    Ok(branch.to_owned())
+}
 
-/// Regex pattern to find the branch/tag pointed to from the manifest.
-const MANIFEST_BRANCH_PATTERN: &str = r"m\/\S* -> (\S*)";
+fn resolve_requested_branch(
+    git: &GitRunner,
+    repo_path: &PathBuf,
+    branch: &str,
+    fallbacks: &[String],
+) -> Result<String> {
+    let branch = existing_fallback(git, repo_path, branch, fallbacks).unwrap_or(branch);
+    if !ref_exists(git, repo_path, branch) {
+        return Err(missing_branch_error(git, repo_path, branch));
+    }
+    verify_ref(git, repo_path, branch)?;
 
-/// Name of subdirectory where merged repository content has to be moved temporarily.
-const TMP_TARGET_PATH: &str = "z_tmp_unique_target_directory_@@@";
+    Ok(branch.to_owned())
+}
 
-/// Merge all repositories below `merge_root` into a adjacent git repository with the given suffix.
-pub fn merge_repositories(
-    merge_root: &str,
-    joined_suffix: &str,
-    branch: Option<&str>,
-) -> Result<()> {
-    let target_path = format!("{}{}", merge_root, joined_suffix);
-    info!("Repositories below {merge_root} will be merge to {target_path}");
+/// Find the first of `fallbacks` which exists if `branch` does not.
+fn existing_fallback<'a>(
+    git: &GitRunner,
+    repo_path: &PathBuf,
+    branch: &str,
+    fallbacks: &'a [String],
+) -> Option<&'a str> {
+    if fallbacks.is_empty() || ref_exists(git, repo_path, branch) {
+        return None;
+    }
+    let fallback = fallbacks
+        .iter()
+        .find(|fallback| ref_exists(git, repo_path, fallback))?;
+    info!(
+        "Branch {branch} not found in {}, using {fallback} instead",
+        repo_path.display()
+    );
+
+    Some(fallback)
+}
+
+/// Check whether `reference` resolves to a commit, treating failures to run git as missing.
+fn ref_exists(git: &GitRunner, repo_path: &PathBuf, reference: &str) -> bool {
+    git.command(repo_path)
+        .args([
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{reference}^{{commit}}"),
+        ])
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Build the error for a branch which does not exist, listing the branches which do.
+///
+/// The closest branch name is suggested if it is similar enough to be a likely typo.
+fn missing_branch_error(git: &GitRunner, repo_path: &PathBuf, branch: &str) -> anyhow::Error {
+    let output = git
+        .command(repo_path)
+        .args(["branch", "--all", "--format=%(refname:short)"])
+        .output()
+        .to_anyhow();
+    let branches = match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && !name.starts_with('('))
+            .map(str::to_owned)
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            warn!(
+                "Failed to list branches of {}: {err:#}",
+                repo_path.display()
+            );
+            Vec::new()
+        }
+    };
+
+    let mut message = format!("branch {branch} not found in {}", repo_path.display());
+    if branches.is_empty() {
+        message.push_str(", it has no branches");
+        return anyhow::anyhow!(message);
+    }
+    message.push_str(&format!(", available branches: {}", branches.join(", ")));
+
+    let closest = branches
+        .iter()
+        .map(|name| (edit_distance(branch, name), name))
+        .min();
+    if let Some((distance, name)) = closest {
+        if distance <= branch.chars().count().max(name.chars().count()) / 2 {
+            message.push_str(&format!(" (did you mean {name}?)"));
+        }
+    }
+
+    anyhow::anyhow!(message)
+}
+
+/// Levenshtein distance of two strings, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, x) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, y) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(x != *y);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
 
-    let repos = find_repos(merge_root).with_context(|| "failed to find repositories")?;
-    info!("Found {} repositories to merge", repos.len());
+    previous[b.len()]
+}
 
-    create_joined_repo(&target_path).with_context(|| "failed to create target repository")?;
+/// Check whether a repository has no commits at all, so that there is nothing to merge.
+fn is_empty_repo(git: &GitRunner, repo_path: &PathBuf) -> Result<bool> {
+    let output = git
+        .command(repo_path)
+        .args(["rev-list", "--count", "--all"])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("counting commits of {} failed", repo_path.display()))?;
 
-    merge_repos(repos.into_iter(), &target_path, merge_root, branch)
-        .with_context(|| "failed to merge repositories")
+    Ok(String::from_utf8_lossy(&output.stdout).trim() == "0")
 }
 
-fn find_repos(root: &str) -> Result<Vec<PathBuf>> {
-    let paths = glob(&format!("{root}/**/.git"))?;
+/// Check whether tracked files of a non-bare repository have uncommitted changes.
+///
+/// Untracked files are ignored, they are neither fetched nor affected by checkouts in general.
+fn is_dirty(git: &GitRunner, repo_path: &PathBuf) -> Result<bool> {
+    if is_bare_repo(git, repo_path)? {
+        return Ok(false);
+    }
 
-    let mut paths: Vec<_> = paths
-        .into_iter()
-        .filter_map(|res| {
-            res.ok()
-                .and_then(|path| path.parent().map(|path| path.to_owned()))
+    let output = git
+        .command(repo_path)
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("status of {} failed", repo_path.display()))?;
+
+    Ok(!output.stdout.is_empty())
+}
+
+/// Stash uncommitted changes if `stash` is set or fail if there are any.
+///
+/// Returns the checked out branch or commit to restore the changes on.
+fn stash_dirty_changes(
+    git: &GitRunner,
+    repo_path: &PathBuf,
+    stash: bool,
+) -> Result<Option<String>> {
+    if !is_dirty(git, repo_path)? {
+        return Ok(None);
+    }
+
+    if !stash {
+        bail!("commit or stash them first, or pass --stash-dirty");
+    }
+
+    let head = git
+        .command(repo_path)
+        .args(["symbolic-ref", "--short", "-q", "HEAD"])
+        .output()
+        .to_anyhow()
+        .or_else(|_| {
+            // Detached HEAD.
+            git.command(repo_path)
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .to_anyhow()
         })
-        .collect();
+        .with_context(|| format!("failed to resolve HEAD in {}", repo_path.display()))?;
+    let head = String::from_utf8_lossy(&head.stdout).trim().to_owned();
 
-    // Make merge order deterministic.
-    paths.sort();
+    git.command(repo_path)
+        .args(["stash", "push", "-m", "trenza: changes stashed before join"])
+        .output()
+        .to_anyhow()
+        .with_context(|| format!("stash in {} failed", repo_path.display()))?;
+    info!("Stashed uncommitted changes in {}", repo_path.display());
 
-    Ok(paths)
+    Ok(Some(head))
 }
 
-fn create_joined_repo(target_path: &str) -> Result<()> {
-    fs::create_dir(target_path)?;
+/// Check out the original branch again and pop the changes stashed before the merge.
+///
+/// The merge already succeeded at this point, so failures are only reported as warnings.
+fn restore_stashed_changes(
+    git: &GitRunner,
+    repo_path: &PathBuf,
+    repo_name: &str,
+    original_head: &str,
+) {
+    let res = git
+        .command(repo_path)
+        .args(["checkout", original_head])
+        .output()
+        .to_anyhow()
+        .and_then(|_| {
+            git.command(repo_path)
+                .args(["stash", "pop"])
+                .output()
+                .to_anyhow()
+        });
+    if let Err(err) = res {
+        warn!(
+            "Failed to restore stashed changes on {original_head} in {repo_name}, they are kept in the \
+             stash: {err:#}"
+        );
+    }
+}
+
+fn is_bare_repo(git: &GitRunner, repo_path: &PathBuf) -> Result<bool> {
+    let output = git
+        .command(repo_path)
+        .args(["rev-parse", "--is-bare-repository"])
+        .output()
+        .to_anyhow()
+        .with_context(|| {
+            format!(
+                "checking for bare repository {} failed",
+                repo_path.display()
+            )
+        })?;
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim() == "true")
+}
 
-    Command::new("git")
-        .current_dir(target_path)
-        .args(["init"])
+/// Check that `reference` resolves to a commit in the repository without modifying it.
+fn verify_ref(git: &GitRunner, repo_path: &PathBuf, reference: &str) -> Result<()> {
+    git.command(repo_path)
+        .args(["rev-parse", "--verify", &format!("{reference}^{{commit}}")])
         .output()
         .to_anyhow()
+        .with_context(|| format!("failed to resolve {reference} in {}", repo_path.display()))
         .map(drop)
 }
 
-fn merge_repos(
-    repos_to_join: impl Iterator<Item = PathBuf>,
-    target_path: &str,
-    root: &str,
-    branch: Option<&str>,
-) -> Result<()> {
-    let mut exclude = HashSet::from([".git".to_owned(), TMP_TARGET_PATH.to_owned()]);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::Identity;
 
-    let mut prepare_branch = match branch {
-        Some(branch) => {
-            let branch = branch.to_owned();
-            Box::new(move |repo_path: &PathBuf| prepare_requested_branch(repo_path, &branch))
-                as Box<dyn FnMut(&PathBuf) -> Result<String>>
+    /// Temporary directory holding the source repositories below `src` and the joined repository,
+    /// removed at the end of the test.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("trenza-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("src")).unwrap();
+            Self(path)
         }
-        None => {
-            let mut manifest_re = Regex::new(MANIFEST_BRANCH_PATTERN).unwrap();
-            Box::new(move |repo_path: &PathBuf| {
-                prepare_manifest_branch(repo_path, &mut manifest_re)
-            }) as Box<dyn FnMut(&PathBuf) -> Result<String>>
+
+        fn root(&self) -> PathBuf {
+            self.0.join("src")
         }
-    };
 
-    for repo_path in repos_to_join {
-        let repo_name = repo_path.strip_prefix(root)?.to_str().unwrap();
-        debug!("Merging repo {repo_name}");
+        fn root_str(&self) -> String {
+            self.root().to_str().unwrap().to_owned()
+        }
 
-        let merge_branch = prepare_branch(&repo_path)?;
-        debug!("Using merge branch {merge_branch} in source repository");
+        fn target(&self) -> PathBuf {
+            self.0.join("src_joined")
+        }
+    }
 
-        Command::new("git")
-            .current_dir(target_path)
-            .args(["remote", "add", repo_name, repo_path.to_str().unwrap()])
-            .output()
-            .to_anyhow()?;
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
 
-        Command::new("git")
-            .current_dir(target_path)
-            .args(["fetch", repo_name])
+    /// Run git in `dir` with a fixed identity and return its stdout.
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .current_dir(dir)
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
             .output()
-            .to_anyhow()?;
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "git {args:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
 
-        Command::new("git")
-            .current_dir(target_path)
-            .args([
-                "merge",
-                &format!("{repo_name}/{merge_branch}"),
-                "--allow-unrelated-histories",
-            ])
-            .output()
-            .to_anyhow()?;
+    /// Create a repository at `path` with `files` committed on `main`.
+    fn create_repo(path: &Path, files: &[(&str, &str)]) {
+        fs::create_dir_all(path).unwrap();
+        git(path, &["init", "--quiet", "--initial-branch", "main"]);
+        for (file, content) in files {
+            let file = path.join(file);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, content).unwrap();
+        }
+        git(path, &["add", "--all"]);
+        git(
+            path,
+            &["commit", "--quiet", "--allow-empty", "-m", "Initial commit"],
+        );
+    }
 
-        move_repo_contents(&exclude, repo_name, target_path)?;
+    /// Paths of all files on `HEAD` of the repository at `path`.
+    fn tracked_files(path: &Path) -> Vec<String> {
+        git(path, &["ls-tree", "-r", "--name-only", "HEAD"])
+            .lines()
+            .map(ToOwned::to_owned)
+            .collect()
+    }
 
-        // Exclude the merged repository from moves in subsequent merges.
-        exclude.insert(repo_name.split('/').next().unwrap().to_owned());
+    fn test_options() -> MergeOptions {
+        MergeOptions {
+            git: GitRunner::default().with_identity(Some(Identity {
+                name: "Test".to_owned(),
+                email: "test@example.com".to_owned(),
+            })),
+            branch: Some("main".to_owned()),
+            ..Default::default()
+        }
+    }
 
-        info!(
-            "Merged repository {repo_name} ({})",
-            repo_path.to_string_lossy()
+    #[test]
+    fn sibling_repos_below_a_shared_parent() {
+        let dir = TestDir::new("siblings");
+        create_repo(&dir.root().join("a/b"), &[("README.md", "b\n")]);
+        create_repo(&dir.root().join("a/c"), &[("README.md", "c\n")]);
+
+        let report = merge_repositories(&dir.root_str(), &test_options()).unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 2);
+        assert_eq!(
+            tracked_files(&dir.target()),
+            ["a/b/README.md", "a/c/README.md"]
         );
     }
 
-    Ok(())
-}
+    #[test]
+    fn deadline_before_the_first_merge_is_not_resumable() {
+        let dir = TestDir::new("deadline");
+        create_repo(&dir.root().join("a"), &[("README.md", "a\n")]);
+        let options = MergeOptions {
+            max_duration: Some(Duration::ZERO),
+            ..test_options()
+        };
 
-fn move_repo_contents(
-    exclude: &HashSet<String>,
-    repo_name: &str,
-    joined_repo_path: &str,
-) -> Result<()> {
-    // Some repositories contain a folder with their own name, e.g. `googletest/googletest`.
-    // To be able to handle them, we move repository content first to a temporary path
-    // and then to the permanent location.
-    let tmp_repo_target_path = format!("{joined_repo_path}/{TMP_TARGET_PATH}");
-    let repo_target_path = format!("{joined_repo_path}/{repo_name}");
-
-    fs::create_dir_all(tmp_repo_target_path)
-        .with_context(|| "failed to create temporary repo target path")?;
-
-    let mut top_level_files: Vec<_> = fs::read_dir(joined_repo_path)
-        .unwrap()
-        .filter_map(|x| {
-            x.ok().and_then(|x| {
-                let name = x.file_name().to_string_lossy().to_string();
-                if exclude.contains(&name) {
-                    None
-                } else {
-                    Some(name)
-                }
-            })
-        })
-        .collect();
-    top_level_files.sort();
+        let err = merge_repositories(&dir.root_str(), &options).unwrap_err();
 
-    debug!("Files to move to {repo_name}:");
-    for file_ in top_level_files.iter() {
-        debug!("  {file_}");
+        let deadline = err.downcast_ref::<DeadlineExceeded>().unwrap();
+        assert!(!deadline.resumable);
+        assert!(!err.to_string().contains("resume"));
+        assert_eq!(git(&dir.target(), &["remote"]), "");
     }
 
-    // Move all merged repository content to temporary path in the joined repository.
-    Command::new("git")
-        .current_dir(joined_repo_path)
-        .args(
-            ["mv".to_owned()]
-                .into_iter()
-                .chain(top_level_files.into_iter())
-                .chain([format!("{TMP_TARGET_PATH}/")]),
+    #[test]
+    fn failing_hooks_fail() {
+        let dir = TestDir::new("hooks");
+        fs::create_dir_all(dir.root()).unwrap();
+
+        assert!(run_hook("test", "exit 0", &dir.root(), &[]).is_ok());
+        assert!(run_hook("test", "exit 1", &dir.root(), &[]).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn hooks_run_with_cmd_on_windows() {
+        let dir = TestDir::new("hooks-windows");
+        fs::create_dir_all(dir.root()).unwrap();
+
+        run_hook(
+            "test",
+            "if not %TRENZA_REPO%==a exit 1",
+            &dir.root(),
+            &[(REPO_ENV, "a")],
         )
-        .output()
-        .to_anyhow()?;
+        .unwrap();
+        assert!(run_hook(
+            "test",
+            "if not %TRENZA_REPO%==b exit 1",
+            &dir.root(),
+            &[(REPO_ENV, "a")]
+        )
+        .is_err());
+    }
 
-    Command::new("git")
-        .current_dir(joined_repo_path)
-        .args(["commit", "-m", &format!("Move {repo_name} repo contents")])
-        .output()
-        .to_anyhow()?;
+    #[test]
+    fn discovery_pattern_finds_bare_repositories() {
+        let dir = TestDir::new("pattern");
+        create_repo(&dir.root().join("a"), &[("README.md", "a\n")]);
+        fs::create_dir_all(dir.root().join("libs")).unwrap();
+        git(&dir.root().join("libs"), &["init", "--bare", "b.git"]);
+        assert_eq!(list_repos(&dir.root_str()).unwrap(), [dir.root().join("a")]);
+
+        let options = DiscoveryOptions {
+            pattern: "libs/*.git".to_owned(),
+            ..DiscoveryOptions::default()
+        };
+        assert_eq!(
+            discover_repositories(&dir.root_str(), &options).unwrap(),
+            [dir.root().join("libs/b.git")]
+        );
 
-    // Move all merged repository content to final location.
-    // We do this only after merging the content to a temporary path because some content may
-    // have the same name as the final location.
-    if repo_name.contains('/') {
-        // Create parent before moving
-        let parent = Path::new(&repo_target_path)
-            .parent()
-            .with_context(|| "failed to find parent of repo target path")?;
-        fs::create_dir_all(parent)?;
+        let options = DiscoveryOptions {
+            pattern: "**/*.hg".to_owned(),
+            ..DiscoveryOptions::default()
+        };
+        assert!(discover_repositories(&dir.root_str(), &options)
+            .unwrap()
+            .is_empty());
     }
 
-    Command::new("git")
-        .current_dir(joined_repo_path)
-        .args(["mv", &format!("{TMP_TARGET_PATH}"), &format!("{repo_name}")])
-        .output()
-        .to_anyhow()?;
+    #[cfg(unix)]
+    #[test]
+    fn files_with_non_utf8_names_are_moved() {
+        use std::os::unix::ffi::OsStrExt;
 
-    Command::new("git")
-        .current_dir(joined_repo_path)
-        .args(["commit", "--amend", "--no-edit"])
-        .output()
-        .to_anyhow()?;
+        let dir = TestDir::new("non-utf8");
+        let repo = dir.root().join("a");
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        fs::create_dir_all(&repo).unwrap();
+        fs::write(repo.join(name), "latin-1\n").unwrap();
+        create_repo(&repo, &[("README.md", "a\n")]);
 
-    Ok(())
-}
+        let report = merge_repositories(&dir.root_str(), &test_options()).unwrap();
 
-fn prepare_manifest_branch(repo_path: &PathBuf, re: &mut Regex) -> Result<String> {
-    // Retrieve remote branches in the source repository.
-    let output = Command::new("git")
-        .current_dir(repo_path)
-        .args(["branch", "-r"])
-        .output()
-        .to_anyhow()?;
-    let remote_branches = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(report.count(RepoStatus::Merged), 1);
+        assert!(dir.target().join("a").join(name).is_file());
+        assert!(!dir.target().join(name).exists());
+        // The moved file is tracked at its new place.
+        assert_eq!(git(&dir.target(), &["status", "--porcelain"]), "");
+    }
 
-    // Find the branch/tag pointed to by the manifest.
-    if let Some(caps) = re.captures(&remote_branches) {
-        let manifest_branch = &caps[1];
+    #[test]
+    fn remote_names_are_sanitized() {
+        let mut taken = HashMap::new();
+        let mut remote =
+            |repo_name| unique_remote_name(repo_name, DEFAULT_REMOTE_NAME_TEMPLATE, &mut taken);
 
-        if manifest_branch.contains('/') {
-            // Regular branch - check it out to have it available for the merge.
-            let manifest_branch = manifest_branch
-                .split('/')
-                .last()
-                .map(ToOwned::to_owned)
-                .with_context(|| "failed to identify manifest branch")?;
+        assert_eq!(remote("my repo"), "my_repo");
+        assert_eq!(remote("libs//foo/"), "libs/foo");
+        assert_eq!(remote(".hidden/.git"), "_hidden/_git");
+        assert_eq!(remote("a..b.lock"), "a__b.lock_");
+        assert_eq!(remote("my:repo"), "my_repo_2");
+    }
 
-            Command::new("git")
-                .current_dir(repo_path)
-                .args(["checkout", &manifest_branch])
-                .output()
-                .to_anyhow()?;
+    #[test]
+    fn path_map_moves_repositories_to_mapped_subdirectories() {
+        let dir = TestDir::new("path-map");
+        create_repo(&dir.root().join("vendor/libfoo"), &[("foo.c", "foo\n")]);
+        create_repo(&dir.root().join("bar"), &[("bar.c", "bar\n")]);
+        let map_file = dir.0.join("paths.map");
+        fs::write(
+            &map_file,
+            "# flatten and deepen\nvendor/libfoo = external\nbar = \"libs/deep/bar\"\n",
+        )
+        .unwrap();
 
-            return Ok(manifest_branch);
-        } else {
-            // The manifest points to a tag - check it out to a temporary branch name.
-            const TMP_JOIN_BRANCH: &str = "tmp_join_branch";
+        let options = MergeOptions {
+            path_map: load_path_map(&map_file).unwrap(),
+            ..test_options()
+        };
+        let report = merge_repositories(&dir.root_str(), &options).unwrap();
 
-            let res = Command::new("git")
-                .current_dir(repo_path)
-                .args(["checkout", "-b", TMP_JOIN_BRANCH, &manifest_branch])
-                .output()
-                .to_anyhow();
+        assert_eq!(report.count(RepoStatus::Merged), 2);
+        assert_eq!(
+            tracked_files(&dir.target()),
+            ["external/foo.c", "libs/deep/bar/bar.c"]
+        );
+    }
 
-            if res.is_err() {
-                warn!("Join branch created from tag already exists, continuing...");
-            }
+    #[test]
+    fn path_map_entries_of_unknown_repositories_are_reported() {
+        let dir = TestDir::new("path-map-unknown");
+        create_repo(&dir.root().join("a"), &[("README.md", "a\n")]);
+        let options = MergeOptions {
+            path_map: HashMap::from([("missing".to_owned(), "b".to_owned())]),
+            ..test_options()
+        };
 
-            return Ok(TMP_JOIN_BRANCH.to_owned());
-        }
+        let checks = check_repositories(&dir.root_str(), &options).unwrap();
+
+        let missing = checks.iter().find(|check| check.repo == "missing").unwrap();
+        assert_eq!(
+            missing.problems,
+            ["configured repository not found below the root"]
+        );
+
+        // The merge of the other repositories is not affected.
+        let report = merge_repositories(&dir.root_str(), &options).unwrap();
+        assert_eq!(report.count(RepoStatus::Merged), 1);
+        assert_eq!(tracked_files(&dir.target()), ["a/README.md"]);
     }
 
-    bail!("failed to find manifest branch")
-}
+    #[test]
+    fn path_maps_outside_the_joined_repository_are_rejected() {
+        let dir = TestDir::new("path-map-invalid");
+        let map_file = dir.0.join("paths.map");
+        fs::write(&map_file, "a = ../outside\n").unwrap();
 
-fn prepare_requested_branch(repo_path: &PathBuf, branch: &str) -> Result<String> {
-    Command::new("git")
-        .current_dir(repo_path)
-        .args(["checkout", branch])
-        .output()
-        .to_anyhow()?;
+        assert!(load_path_map(&map_file).is_err());
+    }
 
-    Ok(branch.to_owned())
+    #[test]
+    fn staging_name_taken_by_a_source_is_not_reused() {
+        let dir = TestDir::new("staging-name");
+        let staged_file = format!("{DEFAULT_STAGING_NAME}/file.txt");
+        create_repo(&dir.root().join("a"), &[(&staged_file, "a\n")]);
+
+        assert_eq!(
+            unused_tmp_name(
+                &dir.root().join("a").to_string_lossy(),
+                DEFAULT_STAGING_NAME
+            ),
+            format!("{DEFAULT_STAGING_NAME}_2")
+        );
+
+        let report = merge_repositories(&dir.root_str(), &test_options()).unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 1);
+        assert_eq!(tracked_files(&dir.target()), [format!("a/{staged_file}")]);
+    }
+
+    #[test]
+    fn gitattributes_lines_are_scoped_to_the_subdirectory() {
+        assert_eq!(
+            root_gitattributes_line("*.psd filter=lfs diff=lfs merge=lfs -text", "libs/a"),
+            Some("libs/a/**/*.psd filter=lfs diff=lfs merge=lfs -text".to_owned())
+        );
+        assert_eq!(
+            root_gitattributes_line("/docs/*.md text eol=lf", "libs/a"),
+            Some("libs/a/docs/*.md text eol=lf".to_owned())
+        );
+        assert_eq!(
+            root_gitattributes_line("\"my file.txt\" -diff", "a"),
+            Some("\"a/**/my file.txt\" -diff".to_owned())
+        );
+        assert_eq!(
+            root_gitattributes_line("[attr]binary -diff -merge -text", "a"),
+            Some("[attr]binary -diff -merge -text".to_owned())
+        );
+        assert_eq!(root_gitattributes_line("# comment", "a"), None);
+    }
+
+    #[test]
+    fn rewrite_gitattributes_combines_them_at_the_root() {
+        let dir = TestDir::new("gitattributes");
+        create_repo(
+            &dir.root().join("a"),
+            &[(
+                ".gitattributes",
+                "*.bin filter=lfs diff=lfs merge=lfs -text\n",
+            )],
+        );
+        create_repo(
+            &dir.root().join("b"),
+            &[(".gitattributes", "*.sh text eol=lf\n")],
+        );
+        let options = MergeOptions {
+            rewrite_gitattributes: true,
+            ..test_options()
+        };
+
+        merge_repositories(&dir.root_str(), &options).unwrap();
+
+        let root = fs::read_to_string(dir.target().join(".gitattributes")).unwrap();
+        assert!(root.contains("a/**/*.bin filter=lfs diff=lfs merge=lfs -text\n"));
+        assert!(root.contains("b/**/*.sh text eol=lf\n"));
+        assert_eq!(
+            git(&dir.target(), &["check-attr", "eol", "b/x/run.sh"]),
+            "b/x/run.sh: eol: lf\n"
+        );
+        assert_eq!(
+            git(&dir.target(), &["check-attr", "eol", "a/run.sh"]),
+            "a/run.sh: eol: unspecified\n"
+        );
+    }
+
+    #[test]
+    fn paths_are_split_into_bounded_batches() {
+        let short: Vec<_> = (0..2500).map(|i| OsString::from(i.to_string())).collect();
+        let batches = path_batches(&short);
+        assert_eq!(
+            batches.iter().map(|batch| batch.len()).collect::<Vec<_>>(),
+            [1000, 1000, 500]
+        );
+
+        let long = vec![OsString::from("x".repeat(6000)); 5];
+        let batches = path_batches(&long);
+        assert_eq!(
+            batches.iter().map(|batch| batch.len()).collect::<Vec<_>>(),
+            [2, 2, 1]
+        );
+        assert!(path_batches(&[]).is_empty());
+    }
+
+    #[test]
+    fn repositories_with_many_root_files_are_moved() {
+        let dir = TestDir::new("many-files");
+        let names: Vec<_> = (0..2500).map(|i| format!("file_{i:04}.txt")).collect();
+        let files: Vec<_> = names.iter().map(|name| (name.as_str(), "")).collect();
+        create_repo(&dir.root().join("a"), &files);
+        create_repo(&dir.root().join("b"), &files);
+
+        let report = merge_repositories(&dir.root_str(), &test_options()).unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 2);
+        let tracked = tracked_files(&dir.target());
+        assert_eq!(tracked.len(), 5000);
+        assert!(tracked
+            .iter()
+            .all(|file| file.starts_with("a/") || file.starts_with("b/")));
+    }
+
+    #[test]
+    fn joined_repository_uses_the_initial_branch() {
+        let dir = TestDir::new("initial-branch");
+        create_repo(&dir.root().join("a"), &[("README.md", "a\n")]);
+        let options = MergeOptions {
+            initial_branch: "trunk".to_owned(),
+            ..test_options()
+        };
+
+        merge_repositories(&dir.root_str(), &options).unwrap();
+
+        assert_eq!(
+            git(&dir.target(), &["symbolic-ref", "--short", "HEAD"]),
+            "trunk\n"
+        );
+    }
+
+    #[test]
+    fn manifest_pointing_to_an_annotated_tag_merges_the_tag() {
+        let dir = TestDir::new("manifest-tag");
+        let repo = dir.root().join("a");
+        create_repo(&repo, &[("README.md", "v1\n")]);
+        git(
+            &repo,
+            &["tag", "--annotate", "v1.0", "--message", "Release 1.0"],
+        );
+        fs::write(repo.join("CHANGELOG.md"), "after the release\n").unwrap();
+        git(&repo, &["add", "CHANGELOG.md"]);
+        git(
+            &repo,
+            &["commit", "--quiet", "--message", "After the release"],
+        );
+        git(
+            &repo,
+            &["symbolic-ref", "refs/remotes/m/main", "refs/tags/v1.0"],
+        );
+        let options = MergeOptions {
+            branch: None,
+            ..test_options()
+        };
+
+        let report = merge_repositories(&dir.root_str(), &options).unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 1);
+        assert_eq!(tracked_files(&dir.target()), ["a/README.md"]);
+        assert_eq!(
+            git(&dir.target(), &["rev-parse", "v1.0^{commit}"]),
+            git(&repo, &["rev-parse", "v1.0^{commit}"])
+        );
+    }
+
+    #[test]
+    fn repositories_with_only_dotfiles_or_an_empty_tree_are_moved() {
+        let dir = TestDir::new("dotfiles");
+        create_repo(
+            &dir.root().join("a"),
+            &[
+                (".github/workflows/ci.yml", "on: push\n"),
+                (".gitignore", "*.log\n"),
+            ],
+        );
+        create_repo(&dir.root().join("b"), &[]);
+        let options = MergeOptions {
+            verify: true,
+            ..test_options()
+        };
+
+        let report = merge_repositories(&dir.root_str(), &options).unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 2);
+        assert_eq!(
+            tracked_files(&dir.target()),
+            ["a/.github/workflows/ci.yml", "a/.gitignore"]
+        );
+        // The empty repository still gets its move commit.
+        let log = git(&dir.target(), &["log", "--format=%s"]);
+        assert!(
+            log.lines().any(|subject| subject == "Move b repo contents"),
+            "{log}"
+        );
+    }
+
+    #[test]
+    fn repo_lists_are_merged_without_discovery() {
+        let dir = TestDir::new("repo-list");
+        create_repo(&dir.root().join("a"), &[("README.md", "a\n")]);
+        create_repo(&dir.root().join("b"), &[("README.md", "b\n")]);
+        let repos = vec![RepoSpec {
+            subdir: Some("libs/a".to_owned()),
+            ..RepoSpec::new(dir.root().join("a"))
+        }];
+
+        let report = MergeBuilder::new()
+            .options(test_options())
+            .run_list(repos, &dir.target())
+            .unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 1);
+        assert_eq!(tracked_files(&dir.target()), ["libs/a/README.md"]);
+    }
+
+    #[test]
+    fn undo_refuses_repositories_without_commits_holding_files() {
+        let dir = TestDir::new("undo-commitless");
+        let target = dir.target();
+        fs::create_dir_all(&target).unwrap();
+        git(&target, &["init", "--quiet"]);
+        fs::write(target.join("notes.txt"), "important\n").unwrap();
+        let target_str = target.to_str().unwrap();
+
+        assert!(undo_merge(target_str, &test_options()).is_err());
+        assert!(target.join("notes.txt").is_file());
+
+        // Even with the state file of a failed run, files are not removed.
+        MergeState::default().save(target_str).unwrap();
+        assert!(undo_merge(target_str, &test_options()).is_err());
+
+        fs::remove_file(target.join("notes.txt")).unwrap();
+        undo_merge(target_str, &test_options()).unwrap();
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn force_refuses_to_replace_commitless_targets_holding_files() {
+        let dir = TestDir::new("force-commitless");
+        create_repo(&dir.root().join("a"), &[("README.md", "a\n")]);
+        let target = dir.target();
+        fs::create_dir_all(&target).unwrap();
+        git(&target, &["init", "--quiet"]);
+        MergeState::default()
+            .save(target.to_str().unwrap())
+            .unwrap();
+        // Ignored files do not show up in the status of the repository.
+        fs::write(target.join(".git/info/exclude"), "notes.txt\n").unwrap();
+        fs::write(target.join("notes.txt"), "important\n").unwrap();
+        let options = MergeOptions {
+            force: true,
+            ..test_options()
+        };
+
+        assert!(merge_repositories(&dir.root_str(), &options).is_err());
+        assert!(target.join("notes.txt").is_file());
+
+        fs::remove_file(target.join("notes.txt")).unwrap();
+        let report = merge_repositories(&dir.root_str(), &options).unwrap();
+        assert_eq!(report.count(RepoStatus::Merged), 1);
+    }
+
+    #[test]
+    fn failed_merges_are_resumed() {
+        let dir = TestDir::new("resume");
+        create_repo(&dir.root().join("a"), &[("README.md", "a\n")]);
+        create_repo(&dir.root().join("b"), &[("README.md", "b\n")]);
+        create_repo(&dir.root().join("c"), &[("README.md", "c\n")]);
+        git(&dir.root().join("b"), &["branch", "--move", "main", "dev"]);
+
+        // Branches are prepared before any repository is merged, so none is merged here.
+        let options = MergeOptions {
+            skip_preflight: true,
+            ..test_options()
+        };
+        assert!(merge_repositories(&dir.root_str(), &options).is_err());
+
+        git(&dir.root().join("b"), &["branch", "--move", "dev", "main"]);
+        let options = MergeOptions {
+            resume: true,
+            ..options
+        };
+        let report = merge_repositories(&dir.root_str(), &options).unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 3);
+        assert_eq!(
+            tracked_files(&dir.target()),
+            ["a/README.md", "b/README.md", "c/README.md"]
+        );
+    }
+
+    #[test]
+    fn resumed_merges_skip_completed_repositories() {
+        let dir = TestDir::new("resume-completed");
+        create_repo(&dir.root().join("a"), &[("README.md", "a\n")]);
+        merge_repositories(&dir.root_str(), &test_options()).unwrap();
+
+        create_repo(&dir.root().join("b"), &[("README.md", "b\n")]);
+        // Partial changes of an interrupted merge are dropped.
+        fs::write(dir.target().join("partial.txt"), "partial\n").unwrap();
+        let options = MergeOptions {
+            resume: true,
+            ..test_options()
+        };
+        let report = merge_repositories(&dir.root_str(), &options).unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 2);
+        assert_eq!(tracked_files(&dir.target()), ["a/README.md", "b/README.md"]);
+        assert!(!dir.target().join("partial.txt").exists());
+        let state = MergeState::load(dir.target().to_str().unwrap()).unwrap();
+        assert_eq!(
+            state
+                .completed
+                .iter()
+                .map(|repo| repo.repo.as_str())
+                .collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+    }
+
+    #[test]
+    fn no_amend_moves_content_in_a_single_commit() {
+        let dir = TestDir::new("no-amend");
+        create_repo(&dir.root().join("a"), &[("README.md", "a\n")]);
+        // The nested directory with the name of the repository needs the temporary path.
+        create_repo(
+            &dir.root().join("b"),
+            &[("b/lib.rs", "b\n"), ("README.md", "b\n")],
+        );
+        let options = MergeOptions {
+            no_amend: true,
+            ..test_options()
+        };
+
+        let report = merge_repositories(&dir.root_str(), &options).unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 2);
+        assert_eq!(
+            tracked_files(&dir.target()),
+            ["a/README.md", "b/README.md", "b/b/lib.rs"]
+        );
+        let changed = git(&dir.target(), &["log", "--name-only", "--format="]);
+        assert!(!changed.contains(DEFAULT_STAGING_NAME), "{changed}");
+        let moves = git(
+            &dir.target(),
+            &["log", "--no-merges", "--format=%s", "--grep=Trenza-Source"],
+        );
+        assert_eq!(moves, "Move b repo contents\nMove a repo contents\n");
+        // Every move commit follows its own merge commit.
+        let parents = git(&dir.target(), &["log", "--format=%p", "-1", "HEAD~1"]);
+        assert_eq!(parents.split_whitespace().count(), 2);
+    }
+
+    #[test]
+    fn repositories_and_files_with_spaces_are_merged() {
+        let dir = TestDir::new("spaces");
+        create_repo(&dir.root().join("my repo"), &[("a b.txt", "a b\n")]);
+        let options = MergeOptions {
+            keep_remotes: true,
+            ..test_options()
+        };
+
+        let report = merge_repositories(&dir.root_str(), &options).unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 1);
+        assert_eq!(tracked_files(&dir.target()), ["my repo/a b.txt"]);
+        assert_eq!(
+            report.remotes,
+            BTreeMap::from([("my_repo".to_owned(), dir.root().join("my repo"))])
+        );
+        assert_eq!(git(&dir.target(), &["remote"]), "my_repo\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn repositories_with_non_utf8_paths_fail_cleanly() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = TestDir::new("non-utf8-repo");
+        let repo = dir.root().join(OsStr::from_bytes(b"caf\xe9"));
+        create_repo(&repo, &[("README.md", "a\n")]);
+
+        let err = merge_repositories(&dir.root_str(), &test_options()).unwrap_err();
+
+        assert!(
+            format!("{err:#}").contains(&format!(
+                "repository path {} is not valid UTF-8",
+                repo.display()
+            )),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn failed_repositories_are_rolled_back_and_reported_in_merge_order() {
+        let dir = TestDir::new("continue-on-error");
+        create_repo(&dir.root().join("a"), &[("README.md", "a\n")]);
+        // Conflicts with the README of a while merging.
+        create_repo(&dir.root().join("b"), &[("README.md", "b\n")]);
+        // Fails while preparing, before any repository is merged.
+        create_repo(&dir.root().join("c"), &[("lib.rs", "c\n")]);
+        git(&dir.root().join("c"), &["branch", "--move", "main", "dev"]);
+        create_repo(&dir.root().join("d"), &[("lib.rs", "d\n")]);
+        let options = MergeOptions {
+            continue_on_error: true,
+            skip_preflight: true,
+            flatten: true,
+            ..test_options()
+        };
+
+        let report = merge_repositories(&dir.root_str(), &options).unwrap();
+
+        let statuses: Vec<_> = report
+            .repos
+            .iter()
+            .map(|repo| (repo.source.file_name().unwrap().to_owned(), repo.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("a".into(), RepoStatus::Merged),
+                ("b".into(), RepoStatus::Failed),
+                ("c".into(), RepoStatus::Failed),
+                ("d".into(), RepoStatus::Merged),
+            ]
+        );
+        assert!(report.repos[1]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("README.md"));
+        assert_eq!(tracked_files(&dir.target()), ["README.md", "lib.rs"]);
+        assert_eq!(
+            fs::read_to_string(dir.target().join("README.md")).unwrap(),
+            "a\n"
+        );
+        assert_eq!(git(&dir.target(), &["status", "--porcelain"]), "");
+        assert!(!dir.target().join(".git/MERGE_HEAD").exists());
+        assert_eq!(git(&dir.target(), &["remote"]), "");
+    }
+
+    #[test]
+    fn ignored_submodules_are_removed() {
+        let dir = TestDir::new("submodules");
+        let repo = dir.root().join("a");
+        let gitmodules = "[submodule \"sub\"]\n\tpath = sub\n\turl = ../sub\n";
+        create_repo(&repo, &[("lib.rs", "a\n"), (".gitmodules", gitmodules)]);
+        let commit = git(&repo, &["rev-parse", "HEAD"]);
+        let gitlink = format!("160000,{},sub", commit.trim());
+        git(&repo, &["update-index", "--add", "--cacheinfo", &gitlink]);
+        git(&repo, &["commit", "--quiet", "--message", "Add submodule"]);
+        // An uninitialized submodule is an empty directory.
+        fs::create_dir(repo.join("sub")).unwrap();
+        let options = MergeOptions {
+            submodules: SubmoduleMode::Ignore,
+            ..test_options()
+        };
+
+        let report = merge_repositories(&dir.root_str(), &options).unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 1);
+        assert_eq!(tracked_files(&dir.target()), ["a/lib.rs"]);
+    }
+
+    #[test]
+    fn untracked_files_of_sources_stay_in_place() {
+        let dir = TestDir::new("untracked");
+        let repo = dir.root().join("a");
+        create_repo(&repo, &[(".gitignore", "*.log\n"), ("README.md", "a\n")]);
+        fs::write(repo.join("build.log"), "log\n").unwrap();
+
+        let report = merge_repositories(&dir.root_str(), &test_options()).unwrap();
+
+        assert_eq!(report.count(RepoStatus::Merged), 1);
+        assert_eq!(
+            tracked_files(&dir.target()),
+            ["a/.gitignore", "a/README.md"]
+        );
+        assert!(!dir.target().join("a/build.log").exists());
+        assert!(!dir.target().join("build.log").exists());
+        assert!(repo.join("build.log").is_file());
+    }
 }
@@ -0,0 +1,109 @@
+use std::{cell::RefCell, fmt, sync::Arc};
+
+use crate::report::RepoStatus;
+
+/// Position of a repository in a merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepoProgress {
+    /// Path of the repository relative to the root.
+    pub repo: String,
+    /// One-based position of the repository in merge order.
+    pub index: usize,
+    /// Number of repositories to merge.
+    pub total: usize,
+}
+
+/// Step of a merge reported to the progress handler.
+///
+/// Fetches may run concurrently, so `Fetched` events can arrive out of merge order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeEvent {
+    /// Work on the repository started with preparing its branch.
+    RepoStarted(RepoProgress),
+    /// The repository was fetched into the joined repository.
+    Fetched(RepoProgress),
+    /// The repository branch was merged into the joined repository.
+    Merged(RepoProgress),
+    /// The repository content was moved to its subdirectory.
+    Moved(RepoProgress),
+    /// Work on the repository is done, with its final status.
+    RepoFinished(RepoProgress, RepoStatus),
+}
+
+impl MergeEvent {
+    pub fn progress(&self) -> &RepoProgress {
+        match self {
+            Self::RepoStarted(progress)
+            | Self::Fetched(progress)
+            | Self::Merged(progress)
+            | Self::Moved(progress)
+            | Self::RepoFinished(progress, _) => progress,
+        }
+    }
+}
+
+impl fmt::Display for MergeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Self::RepoStarted(_) => "started",
+            Self::Fetched(_) => "fetched",
+            Self::Merged(_) => "merged",
+            Self::Moved(_) => "moved",
+            Self::RepoFinished(..) => "finished",
+        };
+        let RepoProgress { repo, index, total } = self.progress();
+
+        write!(f, "[{index}/{total}] {action} {repo}")
+    }
+}
+
+/// Callback invoked for every [`MergeEvent`].
+///
+/// Fetch events are reported from worker threads, so the callback has to be thread-safe.
+#[derive(Clone)]
+pub struct ProgressHandler(Arc<dyn Fn(&MergeEvent) + Send + Sync>);
+
+impl ProgressHandler {
+    pub fn new(handler: impl Fn(&MergeEvent) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(handler))
+    }
+
+    pub(crate) fn report(&self, event: MergeEvent) {
+        (self.0)(&event)
+    }
+}
+
+impl fmt::Debug for ProgressHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ProgressHandler").finish_non_exhaustive()
+    }
+}
+
+thread_local! {
+    static CURRENT_REPO: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Repository the current thread works on, e.g. to add it to the log records of the thread.
+///
+/// It is set while a repository is prepared, fetched and merged.
+pub fn current_repo() -> Option<String> {
+    CURRENT_REPO.with(|repo| repo.borrow().clone())
+}
+
+/// Sets the [`current_repo`] of the current thread until it is dropped.
+pub(crate) struct RepoScope {
+    previous: Option<String>,
+}
+
+impl RepoScope {
+    pub(crate) fn enter(repo: &str) -> Self {
+        let previous = CURRENT_REPO.with(|current| current.replace(Some(repo.to_owned())));
+        Self { previous }
+    }
+}
+
+impl Drop for RepoScope {
+    fn drop(&mut self) {
+        CURRENT_REPO.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
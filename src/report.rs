@@ -0,0 +1,130 @@
+use std::{collections::BTreeMap, fmt, path::PathBuf};
+
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+/// Summary of a merge of several repositories into a joined repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeReport {
+    /// Path of the joined repository.
+    pub target: PathBuf,
+    /// Repositories in merge order.
+    pub repos: Vec<RepoReport>,
+    /// Remotes added to the joined repository, mapped to the path of their source repository.
+    ///
+    /// Only set if the remotes are kept after the merge, e.g. to fetch updates of the sources
+    /// later.
+    pub remotes: BTreeMap<String, PathBuf>,
+}
+
+/// Outcome for a single source repository.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RepoReport {
+    /// Path of the source repository.
+    pub source: PathBuf,
+    /// Branch merged from the source repository.
+    pub branch: String,
+    /// Subdirectory of the joined repository containing the repository content.
+    pub subdir: String,
+    /// Commit of the source repository which was merged, unknown for dry runs.
+    pub commit: Option<String>,
+    pub status: RepoStatus,
+    /// Error the repository failed with, only set for failed repositories.
+    pub error: Option<String>,
+    /// The content of the subdirectory was verified to match the tree of the merged commit.
+    pub verified: bool,
+}
+
+/// Outcome of checking a repository, or one of its extra branches, before a merge.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RepoCheck {
+    /// Path of the repository relative to the root.
+    pub repo: String,
+    /// Branch which would be merged, unknown if it could not be resolved.
+    pub branch: Option<String>,
+    /// Subdirectory of the joined repository for the branch.
+    pub subdir: String,
+    /// The repository has no commits and would be skipped.
+    pub empty: bool,
+    /// Problems which would make the merge of the repository fail.
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepoStatus {
+    /// The repository was merged into the joined repository.
+    Merged,
+    /// The repository was left out, e.g. because of merge conflicts.
+    Skipped,
+    /// The repository would be merged, but this was only a dry run.
+    Planned,
+    /// The repository content was staged in the joined repository without committing it.
+    Staged,
+    /// Merging the repository failed and it was left out, see [`RepoReport::error`].
+    Failed,
+}
+
+impl MergeReport {
+    /// Number of repositories with the given status.
+    pub fn count(&self, status: RepoStatus) -> usize {
+        self.repos
+            .iter()
+            .filter(|repo| repo.status == status)
+            .count()
+    }
+}
+
+impl Serialize for MergeReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut report = serializer.serialize_struct("MergeReport", 6)?;
+        report.serialize_field("target", &self.target)?;
+        report.serialize_field("repo_count", &self.repos.len())?;
+        report.serialize_field("merged_count", &self.count(RepoStatus::Merged))?;
+        report.serialize_field("failed_count", &self.count(RepoStatus::Failed))?;
+        report.serialize_field("repos", &self.repos)?;
+        report.serialize_field("remotes", &self.remotes)?;
+        report.end()
+    }
+}
+
+impl fmt::Display for RepoStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            Self::Merged => "merged",
+            Self::Skipped => "skipped",
+            Self::Planned => "planned",
+            Self::Staged => "staged",
+            Self::Failed => "failed",
+        };
+        f.pad(status)
+    }
+}
+
+impl fmt::Display for MergeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Merged {} of {} repositories into {}",
+            self.count(RepoStatus::Merged),
+            self.repos.len(),
+            self.target.display()
+        )?;
+
+        for repo in self.repos.iter() {
+            write!(
+                f,
+                "  {:<8} {} ({}) -> {}",
+                repo.status,
+                repo.source.display(),
+                repo.branch,
+                repo.subdir
+            )?;
+            if repo.verified {
+                write!(f, " (verified)")?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Path of the state file relative to the joined repository.
+///
+/// It is kept inside `.git` so that it is never moved or committed with repository content.
+pub const STATE_FILE: &str = ".git/trenza-state.json";
+
+/// Progress of a merge, written after every repository so that interrupted merges can resume.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeState {
+    /// Repositories which were merged and moved in merge order.
+    pub completed: Vec<CompletedRepo>,
+    /// Commit of the joined repository before the first repository was merged, if it had one.
+    #[serde(default)]
+    pub initial_head: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompletedRepo {
+    /// Path of the repository relative to the root.
+    pub repo: String,
+    /// Branch merged from the repository.
+    pub branch: String,
+    /// Subdirectory of the joined repository containing the repository content.
+    pub subdir: String,
+    /// Commit of the repository which was merged.
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// Commit of the joined repository after the repository was moved.
+    pub head: String,
+}
+
+impl MergeState {
+    /// Load the state of the joined repository at `target_path`.
+    pub fn load(target_path: &str) -> Result<Self> {
+        let path = Path::new(target_path).join(STATE_FILE);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read merge state {}", path.display()))?;
+
+        serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse merge state {}", path.display()))
+    }
+
+    /// Write the state to the joined repository at `target_path`.
+    pub fn save(&self, target_path: &str) -> Result<()> {
+        let path = Path::new(target_path).join(STATE_FILE);
+        let content = serde_json::to_string_pretty(self)?;
+
+        fs::write(&path, content)
+            .with_context(|| format!("failed to write merge state {}", path.display()))
+    }
+
+    /// Find the completed merge of `repo` into `subdir`.
+    ///
+    /// A repository can be merged several times with different branches into different
+    /// subdirectories.
+    pub fn get(&self, repo: &str, subdir: &str) -> Option<&CompletedRepo> {
+        self.completed
+            .iter()
+            .find(|completed| completed.repo == repo && completed.subdir == subdir)
+    }
+}